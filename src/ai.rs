@@ -1,52 +1,64 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{anyhow, Result};
 use async_openai::{
+    error::{ApiError, OpenAIError},
     types::{
-        ChatCompletionRequestMessage, CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs,
+        ChatCompletionRequestMessage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+        CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+        CreateEmbeddingRequestArgs, Stop,
     },
     Client,
 };
-use tiktoken_rs::tiktoken::{cl100k_base, CoreBPE};
+use async_trait::async_trait;
+use backoff::ExponentialBackoff;
+use eventsource_stream::Eventsource;
+use futures::{Stream, StreamExt};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::tiktoken::CoreBPE;
 use tracing::trace;
 
-use crate::conversation::ConversationCtx;
-
-pub static GPT_MODEL: &str = "gpt-3.5-turbo";
-pub static EMBEDDING_MODEL: &str = "text-embedding-ada-002";
-pub static CHAT_GPT_LIMIT: usize = 4096;
+use crate::{
+    conversation::ConversationCtx,
+    models::{lookup, GenerationConfig, ModelSpec, Tokenizer},
+    retry::{parse_retry_after, ApiFailure},
+};
 
-/// Calculate tokens consumed in the chat api of openai. Check the calculation algorithm here:
-/// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+/// Calculate tokens consumed in the chat api of openai by `messages` sent to `model`. Unknown
+/// models are counted like the default `ModelSpec`.
 pub fn num_tokens_from_messages(
     messages: &VecDeque<ChatCompletionRequestMessage>,
     model: &str,
 ) -> Result<usize> {
-    let bpe = cl100k_base()?;
-    let mut num_tokens = 0;
-    if model == "gpt-3.5-turbo-0301" {
-        for msg in messages.iter() {
-            num_tokens += 4;
-            num_tokens += bpe.encode_with_special_tokens(&msg.content).len();
-            num_tokens += bpe.encode_with_special_tokens(&msg.role.to_string()).len();
-            if let Some(name) = &msg.name {
-                num_tokens += bpe.encode_with_special_tokens(name).len() - 1;
-            }
-        }
-        num_tokens += 2;
-    }
-
-    Ok(num_tokens)
+    let model = lookup(model, &HashMap::new()).unwrap_or_default();
+    TokenEncoder::for_tokenizer(model.tokenizer)?.num_tokens_from_messages(messages, &model)
 }
 
 pub struct TokenEncoder(pub CoreBPE);
 
 impl TokenEncoder {
     pub fn new() -> Result<Self> {
-        Ok(Self(cl100k_base()?))
+        Self::for_tokenizer(Tokenizer::Cl100kBase)
     }
 
-    pub fn num_tokens_from_message(&self, message: &ChatCompletionRequestMessage) -> Result<usize> {
+    pub fn for_tokenizer(tokenizer: Tokenizer) -> Result<Self> {
+        Ok(Self(tokenizer.encoding()?))
+    }
+
+    /// Tokens of the content, role and name of `message`, without the overhead `model` adds to
+    /// every message.
+    pub fn num_tokens_from_message(
+        &self,
+        message: &ChatCompletionRequestMessage,
+        model: &ModelSpec,
+    ) -> Result<usize> {
         let mut num_tokens = 0;
         num_tokens += self.0.encode_with_special_tokens(&message.content).len();
         num_tokens += self
@@ -54,7 +66,8 @@ impl TokenEncoder {
             .encode_with_special_tokens(&message.role.to_string())
             .len();
         if let Some(name) = &message.name {
-            num_tokens += self.0.encode_with_special_tokens(name).len() - 1;
+            let name_tokens = self.0.encode_with_special_tokens(name).len() as i64;
+            num_tokens += (name_tokens + model.tokens_per_name).max(0) as usize;
         }
 
         Ok(num_tokens)
@@ -63,42 +76,325 @@ impl TokenEncoder {
     pub fn num_tokens_from_messages(
         &self,
         messages: &VecDeque<ChatCompletionRequestMessage>,
+        model: &ModelSpec,
     ) -> Result<usize> {
         let mut num_tokens = 0;
         for msg in messages.iter() {
-            num_tokens += 4;
-            num_tokens += self.num_tokens_from_message(msg)?;
+            num_tokens += model.tokens_per_message;
+            num_tokens += self.num_tokens_from_message(msg, model)?;
         }
-        num_tokens += 2;
+        num_tokens += model.tokens_per_reply;
 
         Ok(num_tokens)
     }
+
+    /// The tokens probably consumed by answering `prompt_tokens` with `content`, for answers the
+    /// api doesn't report the usage of, like streamed ones.
+    pub fn estimate_usage(&self, prompt_tokens: usize, content: &str) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens: prompt_tokens as u64,
+            completion_tokens: self.0.encode_with_special_tokens(content).len() as u64,
+        }
+    }
 }
 
-pub struct Openai(pub Client, pub TokenEncoder);
+/// Encoders of the tokenizers in use, built when first needed since building one takes a while.
+#[derive(Default)]
+pub struct TokenEncoders(Mutex<HashMap<Tokenizer, Arc<TokenEncoder>>>);
 
-impl Openai {
-    pub fn new(api_key: &str) -> Result<Self> {
-        Ok(Self(
-            Client::new().with_api_key(api_key),
-            TokenEncoder::new()?,
-        ))
+impl TokenEncoders {
+    pub fn get(&self, tokenizer: Tokenizer) -> Result<Arc<TokenEncoder>> {
+        let mut encoders = match self.0.lock() {
+            Ok(encoders) => encoders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(encoder) = encoders.get(&tokenizer) {
+            return Ok(encoder.clone());
+        }
+        let encoder = Arc::new(TokenEncoder::for_tokenizer(tokenizer)?);
+        encoders.insert(tokenizer, encoder.clone());
+        Ok(encoder)
+    }
+}
+
+/// Tokens consumed by a chat completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
     }
+}
+
+/// The answer of a chat completion, with the tokens consumed as reported in the `usage` field of
+/// the response, if the api reports it.
+#[derive(Debug, Clone)]
+pub struct ChatResponse {
+    pub content: String,
+    pub usage: Option<TokenUsage>,
+}
+
+/// Content deltas of a chat completion, in the order they are generated.
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+/// A backend which is able to continue a chat conversation with a model.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn chat_complete(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatResponse>;
+
+    async fn chat_complete_stream(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatStream>;
+}
+
+/// A backend which is able to turn text into an embedding vector.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    async fn embedding(&self, text: &str) -> Result<Vec<f32>>;
+
+    /// Embeddings of several texts with one request, in the order of `texts`.
+    async fn embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+
+    /// The embedding model requested from the api.
+    fn model(&self) -> &str;
+}
+
+/// A chat completion request with the parameters `CreateChatCompletionRequest` lacks.
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    #[serde(flatten)]
+    request: CreateChatCompletionRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u16>,
+}
+
+fn chat_request(
+    model: &str,
+    generation: &GenerationConfig,
+    conversation: ConversationCtx,
+    stream: bool,
+) -> Result<ChatRequest> {
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(model).messages(conversation.value);
+    if let Some(temperature) = generation.temperature {
+        args.temperature(temperature);
+    }
+    if let Some(top_p) = generation.top_p {
+        args.top_p(top_p);
+    }
+    if !generation.stop.is_empty() {
+        args.stop(Stop::StringArray(generation.stop.clone()));
+    }
+    if stream {
+        args.stream(true);
+    }
+    Ok(ChatRequest {
+        request: args.build()?,
+        max_tokens: generation.max_tokens,
+    })
+}
+
+/// The chat completion endpoint of the api of `client`, which is requested directly with `http`
+/// since the client can't send `max_tokens`.
+fn post_chat(
+    http: &reqwest::Client,
+    client: &Client,
+    request: &ChatRequest,
+) -> reqwest::RequestBuilder {
+    http.post(format!("{}/chat/completions", client.api_base()))
+        .bearer_auth(client.api_key())
+        .json(request)
+}
+
+/// The error object of a failed request.
+#[derive(Deserialize)]
+struct WrappedError {
+    error: ApiError,
+}
+
+/// The failure of a `response` with an error status, with the time to wait its `Retry-After`
+/// header asks for. A body which isn't an error object is taken as the message.
+async fn api_failure(response: reqwest::Response) -> anyhow::Error {
+    let retry_after = match response.status() {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| parse_retry_after(x, SystemTime::now())),
+        _ => None,
+    };
+    let status = response.status();
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(why) => return OpenAIError::Reqwest(why).into(),
+    };
+    // Proxies in front of the api answer with error pages instead of error objects.
+    let error = match serde_json::from_slice::<WrappedError>(&bytes) {
+        Ok(wrapped) => wrapped.error,
+        Err(_) => ApiError {
+            message: match String::from_utf8_lossy(&bytes).trim() {
+                "" => "No body".to_string(),
+                body => body.to_string(),
+            },
+            r#type: status.to_string(),
+            param: None,
+            code: None,
+        },
+    };
+    ApiFailure {
+        status,
+        error: OpenAIError::ApiError(error),
+        retry_after,
+    }
+    .into()
+}
+
+async fn create_chat_completion(
+    http: &reqwest::Client,
+    client: &Client,
+    model: &str,
+    generation: &GenerationConfig,
+    conversation: ConversationCtx,
+) -> Result<ChatResponse> {
+    let request = chat_request(model, generation, conversation, false)?;
+    let response = post_chat(http, client, &request)
+        .send()
+        .await
+        .map_err(OpenAIError::Reqwest)?;
+    if !response.status().is_success() {
+        return Err(api_failure(response).await);
+    }
+    let bytes = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+    let mut response: CreateChatCompletionResponse =
+        serde_json::from_slice(&bytes).map_err(OpenAIError::JSONDeserialize)?;
+    if let Some(choice) = response.choices.pop() {
+        trace!("{}", &choice.message.content);
+        Ok(ChatResponse {
+            content: choice.message.content,
+            usage: response.usage.map(|x| TokenUsage {
+                prompt_tokens: x.prompt_tokens.into(),
+                completion_tokens: x.completion_tokens.into(),
+            }),
+        })
+    } else {
+        Err(anyhow!("No chat response from {}", client.api_base()))
+    }
+}
+
+async fn create_chat_completion_stream(
+    http: &reqwest::Client,
+    client: &Client,
+    model: &str,
+    generation: &GenerationConfig,
+    conversation: ConversationCtx,
+) -> Result<ChatStream> {
+    let request = chat_request(model, generation, conversation, true)?;
+    // The status is checked before the events are read, so a refused request fails here like
+    // a non streamed one instead of with the first delta.
+    let response = post_chat(http, client, &request)
+        .send()
+        .await
+        .map_err(OpenAIError::Reqwest)?;
+    if !response.status().is_success() {
+        return Err(api_failure(response).await);
+    }
+    let deltas = response
+        .bytes_stream()
+        .eventsource()
+        .take_while(|event| {
+            let done = matches!(event, Ok(event) if event.data == "[DONE]");
+            future::ready(!done)
+        })
+        .filter_map(|event| async move {
+            match event {
+                Ok(event) => {
+                    match serde_json::from_str::<CreateChatCompletionStreamResponse>(&event.data) {
+                        Ok(mut response) => response
+                            .choices
+                            .pop()
+                            .and_then(|choice| choice.delta.content)
+                            .map(Ok),
+                        Err(why) => Some(Err(OpenAIError::JSONDeserialize(why).into())),
+                    }
+                }
+                Err(why) => Some(Err(OpenAIError::StreamError(why.to_string()).into())),
+            }
+        });
+    Ok(Box::pin(deltas))
+}
 
+async fn create_embedding(client: &Client, model: &str, text: &str) -> Result<Vec<f32>> {
+    trace!("Get embedding for '{}'", text);
+    let request = CreateEmbeddingRequestArgs::default()
+        .model(model)
+        .input(text)
+        .build()?;
+
+    let mut response = client.embeddings().create(request).await?;
+
+    if let Some(data) = response.data.pop() {
+        Ok(data.embedding)
+    } else {
+        Err(anyhow!("No embedding response from {}", client.api_base()))
+    }
+}
+
+async fn create_embeddings(
+    client: &Client,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    trace!("Get embeddings for {} texts", texts.len());
+    let request = CreateEmbeddingRequestArgs::default()
+        .model(model)
+        .input(texts.to_vec())
+        .build()?;
+
+    let mut response = client.embeddings().create(request).await?;
+    if response.data.len() != texts.len() {
+        return Err(anyhow!(
+            "Expect {} embeddings from {}, got {}",
+            texts.len(),
+            client.api_base(),
+            response.data.len()
+        ));
+    }
+
+    response.data.sort_by_key(|x| x.index);
+    Ok(response.data.into_iter().map(|x| x.embedding).collect())
+}
+
+impl TokenEncoder {
+    /// Drop the oldest messages after the system message until the conversation leaves
+    /// `completion_tokens` of the context of `model` for the answer.
     pub fn shrink_conversation<'a>(
-        &'a self,
+        &self,
         ctx: &'a mut ConversationCtx,
-        limit: usize,
-    ) -> Result<&mut ConversationCtx> {
+        model: &ModelSpec,
+        completion_tokens: usize,
+    ) -> Result<&'a mut ConversationCtx> {
+        let limit = model.context_window.saturating_sub(completion_tokens);
         let mut messages_count = VecDeque::with_capacity(ctx.value.len());
         let mut tokens: usize = 0;
         for msg in ctx.value.iter() {
-            tokens += 4;
-            let num_tokens = self.1.num_tokens_from_message(msg)?;
+            tokens += model.tokens_per_message;
+            let num_tokens = self.num_tokens_from_message(msg, model)?;
             tokens += num_tokens;
             messages_count.push_back(num_tokens);
         }
-        tokens += 2;
+        tokens += model.tokens_per_reply;
 
         if tokens <= limit {
             return Ok(ctx);
@@ -109,7 +405,7 @@ impl Openai {
             let mut i = 0;
             while tokens > limit && messages_count.len() > 1 {
                 tokens -= messages_count[0];
-                tokens -= 4;
+                tokens -= model.tokens_per_message;
                 messages_count.pop_front();
                 i += 1;
             }
@@ -124,42 +420,96 @@ impl Openai {
     }
 }
 
+/// A client which leaves retrying failed requests to `retry::Retrying`, instead of retrying rate
+/// limited requests on its own.
+fn client_without_retries() -> Client {
+    Client::new().with_backoff(ExponentialBackoff {
+        max_elapsed_time: Some(Duration::ZERO),
+        ..Default::default()
+    })
+}
+
+/// OpenAI, or any server speaking its http api at `api_base`, e.g. a self-hosted model behind
+/// LocalAI, vLLM or llama.cpp.
+pub struct Openai {
+    pub client: Client,
+    /// Sends the chat requests, keeping its connections to the api open between them.
+    pub http: reqwest::Client,
+    pub embedding_model: String,
+}
+
 impl Openai {
-    pub async fn chat_complete(&self, conversation: ConversationCtx) -> Result<String> {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(GPT_MODEL)
-            .messages(conversation.value)
-            .build()?;
-        let mut response = self.0.chat().create(request).await?;
-        if let Some(choice) = response.choices.pop() {
-            trace!("{}", &choice.message.content);
-            Ok(choice.message.content)
-        } else {
-            Err(anyhow!("No chat response from OpenAI"))
+    pub fn new(api_base: Option<&str>, api_key: &str, embedding_model: &str) -> Self {
+        let client = match api_base {
+            Some(api_base) => {
+                client_without_retries().with_api_base(api_base.trim_end_matches('/'))
+            }
+            None => client_without_retries(),
+        };
+        Self {
+            client: client.with_api_key(api_key),
+            http: reqwest::Client::new(),
+            embedding_model: embedding_model.into(),
         }
     }
+}
 
-    pub async fn embedding(&self, text: &str) -> Result<Vec<f32>> {
-        trace!("Get embedding for '{}'", text);
-        let request = CreateEmbeddingRequestArgs::default()
-            .model(EMBEDDING_MODEL)
-            .input(text)
-            .build()?;
+#[async_trait]
+impl ChatProvider for Openai {
+    async fn chat_complete(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatResponse> {
+        create_chat_completion(&self.http, &self.client, model, generation, conversation).await
+    }
 
-        let mut response = self.0.embeddings().create(request).await?;
+    async fn chat_complete_stream(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatStream> {
+        create_chat_completion_stream(&self.http, &self.client, model, generation, conversation)
+            .await
+    }
+}
 
-        if let Some(data) = response.data.pop() {
-            Ok(data.embedding)
-        } else {
-            Err(anyhow!("No embedding response from OpenAI"))
-        }
+#[async_trait]
+impl EmbeddingProvider for Openai {
+    async fn embedding(&self, text: &str) -> Result<Vec<f32>> {
+        create_embedding(&self.client, &self.embedding_model, text).await
+    }
+
+    async fn embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        create_embeddings(&self.client, &self.embedding_model, texts).await
+    }
+
+    fn model(&self) -> &str {
+        &self.embedding_model
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Openai;
-    use crate::conversation::ConversationCtx;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{
+        chat_request, num_tokens_from_messages, ChatProvider, EmbeddingProvider, Openai,
+        TokenEncoder,
+    };
+    use crate::{
+        conversation::ConversationCtx,
+        models::{lookup, GenerationConfig, ModelSpec},
+        retry::ApiFailure,
+    };
 
     fn data() -> ConversationCtx {
         let mut ctx = ConversationCtx::default();
@@ -172,33 +522,53 @@ mod tests {
         ctx
     }
 
+    /// gpt-3.5-turbo with a context of `context_window` tokens.
+    fn turbo(context_window: usize) -> ModelSpec {
+        ModelSpec {
+            context_window,
+            ..lookup("gpt-3.5-turbo", &Default::default()).unwrap()
+        }
+    }
+
     #[test]
     fn test_token_calculation() {
         let ctx = data();
-        let ai = Openai::new("test").unwrap();
-        let nums = ai.1.num_tokens_from_messages(&ctx.value).unwrap();
+        let encoder = TokenEncoder::new().unwrap();
+        let nums = encoder
+            .num_tokens_from_messages(&ctx.value, &turbo(4096))
+            .unwrap();
         assert_eq!(nums, 126);
+        assert_eq!(
+            num_tokens_from_messages(&ctx.value, "gpt-3.5-turbo-0301").unwrap(),
+            126
+        );
+        assert_eq!(num_tokens_from_messages(&ctx.value, "gpt-4").unwrap(), 129);
     }
 
     #[test]
     fn test_shrink_conversation() {
-        let ai = Openai::new("test").unwrap();
+        let encoder = TokenEncoder::new().unwrap();
         let mut ctx = data();
-        let result = ai.shrink_conversation(&mut ctx, 125);
+        let result = encoder.shrink_conversation(&mut ctx, &turbo(125), 0);
         assert!(result.is_ok());
 
         let mut ctx = data();
-        let result = ai.shrink_conversation(&mut ctx, 49);
+        let result = encoder.shrink_conversation(&mut ctx, &turbo(49), 0);
         assert!(result.is_ok());
         assert!(result.unwrap().value.len() == 2);
         assert_eq!(ctx.value[1].content, "This late pivot means we don't have time to boil the ocean for the client deliverable.");
 
         let mut ctx = data();
-        let result = ai.shrink_conversation(&mut ctx, 48);
+        let result = encoder.shrink_conversation(&mut ctx, &turbo(48), 0);
         assert!(result.is_err());
 
+        // The room kept for the answer is taken from the context.
         let mut ctx = data();
-        let result = ai.shrink_conversation(&mut ctx, 71);
+        let result = encoder.shrink_conversation(&mut ctx, &turbo(4096), 4096 - 49);
+        assert_eq!(result.unwrap().value.len(), 2);
+
+        let mut ctx = data();
+        let result = encoder.shrink_conversation(&mut ctx, &turbo(71), 0);
         assert!(result.is_ok());
         assert!(result.unwrap().value.len() == 3);
         assert_eq!(
@@ -206,4 +576,149 @@ mod tests {
             "Let's talk later when we're less busy about how to do better."
         );
     }
+
+    #[test]
+    fn test_chat_request() {
+        let generation = GenerationConfig {
+            temperature: Some(0.2),
+            top_p: None,
+            max_tokens: Some(300),
+            stop: vec!["\nQuestion:".into()],
+        };
+        let request = chat_request("gpt-4", &generation, data(), false).unwrap();
+        let request = serde_json::to_value(&request).unwrap();
+        assert_eq!(request["model"], "gpt-4");
+        assert_eq!(request["max_tokens"], 300);
+        assert_eq!(request["stop"], serde_json::json!(["\nQuestion:"]));
+        assert!(request.get("top_p").is_none());
+
+        let request = chat_request("gpt-4", &GenerationConfig::default(), data(), true).unwrap();
+        let request = serde_json::to_value(&request).unwrap();
+        assert!(request.get("max_tokens").is_none());
+        assert_eq!(request["stream"], true);
+    }
+
+    fn stream_body() -> String {
+        ["Hello", " from", " the stand-in"]
+            .iter()
+            .map(|delta| {
+                format!(
+                    "data: {{\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"created\":0,\"model\":\"local\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}},\"finish_reason\":null}}]}}\n\n",
+                    delta
+                )
+            })
+            .chain(std::iter::once("data: [DONE]\n\n".to_string()))
+            .collect()
+    }
+
+    /// Serve canned OpenAI-style responses on a random local port and return its api base.
+    async fn stand_in_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    let (header_end, content_length) = loop {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                        let text = String::from_utf8_lossy(&buf).to_string();
+                        if let Some(end) = text.find("\r\n\r\n") {
+                            let length = text[..end]
+                                .lines()
+                                .find_map(|l| {
+                                    l.to_lowercase()
+                                        .strip_prefix("content-length:")
+                                        .map(|v| v.trim().parse::<usize>().unwrap())
+                                })
+                                .unwrap_or(0);
+                            break (end + 4, length);
+                        }
+                    };
+                    while buf.len() < header_end + content_length {
+                        let n = socket.read(&mut chunk).await.unwrap();
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+
+                    let request = String::from_utf8_lossy(&buf).to_string();
+                    let mut status = "200 OK";
+                    let (content_type, body) = if request.contains(r#""model":"missing""#) {
+                        status = "404 Not Found";
+                        ("text/plain", "No such model".to_string())
+                    } else if request.contains(r#""model":"rate-limited""#) {
+                        status = "429 Too Many Requests\r\nRetry-After: 7";
+                        ("application/json", r#"{"error":{"message":"Rate limit reached","type":"requests","param":null,"code":null}}"#.to_string())
+                    } else if request.contains(r#""stream":true"#) {
+                        ("text/event-stream", stream_body())
+                    } else if request.starts_with("POST /v1/chat/completions") {
+                        ("application/json", r#"{"id":"1","object":"chat.completion","created":0,"model":"local","choices":[{"index":0,"message":{"role":"assistant","content":"Hello from the stand-in"},"finish_reason":"stop"}],"usage":{"prompt_tokens":9,"completion_tokens":5,"total_tokens":14}}"#.to_string())
+                    } else {
+                        ("application/json", r#"{"object":"list","model":"local","data":[{"index":0,"object":"embedding","embedding":[0.1,0.2,0.3]}],"usage":{"prompt_tokens":2,"total_tokens":2}}"#.to_string())
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        content_type,
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        format!("http://{}/v1", addr)
+    }
+
+    #[tokio::test]
+    async fn test_openai_compatible_provider() {
+        let api_base = stand_in_server().await;
+        let provider = Openai::new(Some(&api_base), "test", "local-embedding");
+
+        let response = provider
+            .chat_complete("local-chat", &GenerationConfig::default(), data())
+            .await
+            .unwrap();
+        assert_eq!(response.content, "Hello from the stand-in");
+        assert_eq!(response.usage.map(|x| x.total()), Some(14));
+
+        let embedding = provider.embedding("Hello").await.unwrap();
+        assert_eq!(embedding, vec![0.1, 0.2, 0.3]);
+
+        let why = provider
+            .chat_complete("rate-limited", &GenerationConfig::default(), data())
+            .await
+            .unwrap_err();
+        let failure = why.downcast_ref::<ApiFailure>().unwrap();
+        assert_eq!(failure.retry_after, Some(Duration::from_secs(7)));
+
+        let why = provider
+            .chat_complete("missing", &GenerationConfig::default(), data())
+            .await
+            .unwrap_err();
+        let failure = why.downcast_ref::<ApiFailure>().unwrap();
+        assert_eq!(failure.status.as_u16(), 404);
+        assert_eq!(failure.error.to_string(), "404 Not Found: No such model");
+    }
+
+    #[tokio::test]
+    async fn test_openai_compatible_stream() {
+        let api_base = stand_in_server().await;
+        let provider = Openai::new(Some(&api_base), "test", "local-embedding");
+
+        let stream = provider
+            .chat_complete_stream("local-chat", &GenerationConfig::default(), data())
+            .await
+            .unwrap();
+        let deltas: Vec<String> = stream.map(|delta| delta.unwrap()).collect().await;
+        assert_eq!(deltas, vec!["Hello", " from", " the stand-in"]);
+
+        let why = provider
+            .chat_complete_stream("rate-limited", &GenerationConfig::default(), data())
+            .await
+            .err()
+            .unwrap();
+        let failure = why.downcast_ref::<ApiFailure>().unwrap();
+        assert_eq!(failure.retry_after, Some(Duration::from_secs(7)));
+    }
 }
@@ -0,0 +1,388 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    knowledge_base::{KnowledgeClient, UPSERT_BATCH_SIZE},
+    vector_store::{CollectionConfig, Distance, IndexType, Payload, VectorPoint},
+};
+
+/// Version of the backup format, raised when backups of older versions can't be restored as they
+/// are.
+const BACKUP_VERSION: u32 = 1;
+
+/// First line of a backup, describing the collection the points are restored into.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub version: u32,
+    pub collection: String,
+    pub vector_size: u64,
+    pub distance: Distance,
+    /// Type of each indexed payload field.
+    pub indexes: BTreeMap<String, IndexType>,
+    pub points: u64,
+}
+
+/// Id of a point, as Qdrant accepts it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BackupId {
+    Num(u64),
+    Uuid(String),
+}
+
+/// A line of a backup after the header.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupPoint {
+    pub id: BackupId,
+    pub vector: Vec<f32>,
+    pub payload: Payload,
+}
+
+impl From<VectorPoint> for BackupPoint {
+    fn from(point: VectorPoint) -> Self {
+        let id = match point.id.parse::<u64>() {
+            Ok(id) => BackupId::Num(id),
+            Err(_) => BackupId::Uuid(point.id),
+        };
+        Self {
+            id,
+            vector: point.vector,
+            payload: point.payload,
+        }
+    }
+}
+
+impl From<BackupPoint> for VectorPoint {
+    fn from(point: BackupPoint) -> Self {
+        let id = match point.id {
+            BackupId::Num(id) => id.to_string(),
+            BackupId::Uuid(id) => id,
+        };
+        Self {
+            id,
+            vector: point.vector,
+            payload: point.payload,
+        }
+    }
+}
+
+/// Write the points of `collection` with their vectors and payloads into `path`, gzipped JSON
+/// lines after a header, so the collection can be restored without embedding it again. The
+/// backup is written aside and renamed, so a failed backup never replaces an earlier one.
+pub async fn backup(
+    knowledge_client: &KnowledgeClient,
+    collection: &str,
+    path: &Path,
+) -> Result<()> {
+    let info = knowledge_client
+        .collection_info(collection)
+        .await?
+        .ok_or_else(|| anyhow!("No collection {}", collection))?;
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        collection: collection.into(),
+        vector_size: info.config.vector_size,
+        distance: info.config.distance,
+        indexes: info.config.indexes,
+        points: info.points,
+    };
+
+    // The whole file name is kept, so backups differing only in their extension don't collide.
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let points = match write_backup(knowledge_client, &header, &temp).await {
+        Ok(points) => points,
+        Err(why) => {
+            let _ = fs::remove_file(&temp);
+            return Err(why);
+        }
+    };
+    fs::rename(&temp, path)?;
+    info!(
+        "Backed up {} points of {} to {:?}",
+        points, collection, path
+    );
+    Ok(())
+}
+
+async fn write_backup(
+    knowledge_client: &KnowledgeClient,
+    header: &BackupHeader,
+    path: &Path,
+) -> Result<usize> {
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    writeln!(writer, "{}", serde_json::to_string(header)?)?;
+    let mut points = 0;
+    let mut offset = None;
+    loop {
+        let (page, next) = knowledge_client
+            .scroll_page(&header.collection, None, offset.as_deref(), true)
+            .await?;
+        for point in page {
+            writeln!(
+                writer,
+                "{}",
+                serde_json::to_string(&BackupPoint::from(point))?
+            )?;
+            points += 1;
+        }
+        match next {
+            Some(next) => offset = Some(next),
+            None => break,
+        }
+    }
+    writer.finish()?.flush()?;
+    Ok(points)
+}
+
+/// The header of the backup in `path`, and its points.
+fn read_backup(path: &Path) -> Result<(BackupHeader, impl Iterator<Item = Result<BackupPoint>>)> {
+    let mut lines = BufReader::new(GzDecoder::new(File::open(path)?)).lines();
+    let header: BackupHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(anyhow!("{:?} is empty", path)),
+    };
+    if header.version != BACKUP_VERSION {
+        return Err(anyhow!(
+            "Backup version {} isn't supported, expect {}",
+            header.version,
+            BACKUP_VERSION
+        ));
+    }
+    let points = lines.map(|line| Ok(serde_json::from_str(&line?)?));
+    Ok((header, points))
+}
+
+/// The header of the backup in `path`, after reading the whole backup to make sure every point
+/// can be restored.
+fn check_backup(path: &Path) -> Result<BackupHeader> {
+    let (header, points) = read_backup(path)?;
+    let mut count = 0;
+    for (line, point) in points.enumerate() {
+        let point = point.map_err(|why| anyhow!("Line {} of {:?}: {}", line + 2, path, why))?;
+        if point.vector.len() as u64 != header.vector_size {
+            return Err(anyhow!(
+                "Line {} of {:?}: vector has {} dimensions, expect {}",
+                line + 2,
+                path,
+                point.vector.len(),
+                header.vector_size
+            ));
+        }
+        count += 1;
+    }
+    if count != header.points {
+        warn!(
+            "Backup holds {} points, but {} were counted when backing up",
+            count, header.points
+        );
+    }
+    Ok(header)
+}
+
+/// Create `collection` like the backed up one, and load the points of the backup in `path` into
+/// it.
+async fn load_backup(
+    knowledge_client: &KnowledgeClient,
+    path: &Path,
+    collection: &str,
+) -> Result<()> {
+    let (header, lines) = read_backup(path)?;
+    let config = CollectionConfig {
+        vector_size: header.vector_size,
+        distance: header.distance,
+        indexes: header.indexes.clone(),
+    };
+    knowledge_client
+        .create_collection(collection, &config)
+        .await?;
+
+    let mut points = 0;
+    let mut batch = Vec::with_capacity(UPSERT_BATCH_SIZE);
+    for point in lines {
+        batch.push(point?.into());
+        if batch.len() == UPSERT_BATCH_SIZE {
+            points += batch.len();
+            knowledge_client
+                .upsert(collection, std::mem::take(&mut batch))
+                .await?;
+            info!("Restored {} of {} points", points, header.points);
+        }
+    }
+    if !batch.is_empty() {
+        points += batch.len();
+        knowledge_client.upsert(collection, batch).await?;
+    }
+    info!("Restored {} points into {}", points, collection);
+    Ok(())
+}
+
+/// Copy the points of `from` into `to`, which is created like `from`.
+async fn copy_collection(knowledge_client: &KnowledgeClient, from: &str, to: &str) -> Result<()> {
+    let info = knowledge_client
+        .collection_info(from)
+        .await?
+        .ok_or_else(|| anyhow!("No collection {}", from))?;
+    knowledge_client.create_collection(to, &info.config).await?;
+    let mut offset = None;
+    loop {
+        let (page, next) = knowledge_client
+            .scroll_page(from, None, offset.as_deref(), true)
+            .await?;
+        knowledge_client.upsert(to, page).await?;
+        match next {
+            Some(next) => offset = Some(next),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Create a collection like the one backed up in `path`, named `collection` or like the original,
+/// and load the points of the backup into it. The whole backup is checked before anything is
+/// changed. An existing collection is only replaced with `replace`, and only once the backup is
+/// restored completely beside it. The replaced points are copied aside first and kept until the
+/// restored ones are in place.
+pub async fn restore(
+    knowledge_client: &KnowledgeClient,
+    path: &Path,
+    collection: Option<String>,
+    replace: bool,
+) -> Result<()> {
+    let header = check_backup(path)?;
+    let collection = collection.unwrap_or(header.collection);
+    let exists = knowledge_client
+        .collection_info(&collection)
+        .await?
+        .is_some();
+    if exists && !replace {
+        return Err(anyhow!(
+            "Collection {} exists, restore with --replace to replace it",
+            collection
+        ));
+    }
+    if !exists {
+        return match load_backup(knowledge_client, path, &collection).await {
+            Ok(()) => Ok(()),
+            Err(why) => {
+                let _ = knowledge_client.delete_collection(&collection).await;
+                Err(why)
+            }
+        };
+    }
+
+    let staging = format!("{}-restoring", collection);
+    if knowledge_client.collection_info(&staging).await?.is_some() {
+        knowledge_client.delete_collection(&staging).await?;
+    }
+    if let Err(why) = load_backup(knowledge_client, path, &staging).await {
+        let _ = knowledge_client.delete_collection(&staging).await;
+        return Err(why.context(format!(
+            "Restoring failed, {} is left as it was",
+            collection
+        )));
+    }
+    let replaced = format!("{}-replaced", collection);
+    if knowledge_client.collection_info(&replaced).await?.is_some() {
+        knowledge_client.delete_collection(&replaced).await?;
+    }
+    if let Err(why) = copy_collection(knowledge_client, &collection, &replaced).await {
+        let _ = knowledge_client.delete_collection(&replaced).await;
+        return Err(why.context(format!(
+            "Restoring failed, {} is left as it was and the restored points are kept in {}",
+            collection, staging
+        )));
+    }
+    warn!("Replacing collection {}", collection);
+    knowledge_client.delete_collection(&collection).await?;
+    copy_collection(knowledge_client, &staging, &collection)
+        .await
+        .map_err(|why| {
+            why.context(format!(
+                "The restored points are kept in {}, the replaced ones in {}",
+                staging, replaced
+            ))
+        })?;
+    knowledge_client.delete_collection(&staging).await?;
+    knowledge_client.delete_collection(&replaced).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use serde_json::json;
+
+    use super::{backup, restore, BackupId, BackupPoint};
+    use crate::{
+        knowledge_base::KnowledgeClient,
+        sqlite_vector_store::SqliteVectorStore,
+        vector_store::{CollectionConfig, Distance, IndexType, VectorPoint},
+    };
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let client = KnowledgeClient::new(Arc::new(SqliteVectorStore::open(":memory:").unwrap()));
+        let config = CollectionConfig {
+            vector_size: 2,
+            distance: Distance::Dot,
+            indexes: BTreeMap::from([("doc_id".to_string(), IndexType::Keyword)]),
+        };
+        client.create_collection("kb", &config).await.unwrap();
+        let point = VectorPoint {
+            id: "5c56c793-69f3-4fbf-87e6-c4bf54c28c26".into(),
+            vector: vec![0.25, -1.0],
+            payload: json!({"doc_id": "a", "chunk_index": 3, "tags": ["faq"]})
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        client.upsert("kb", vec![point.clone()]).await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("kb-{}.backup.gz", uuid::Uuid::new_v4()));
+        backup(&client, "kb", &path).await.unwrap();
+        assert!(restore(&client, &path, None, false).await.is_err());
+        restore(&client, &path, Some("copy".into()), false)
+            .await
+            .unwrap();
+
+        // A broken backup leaves the collection to replace alone.
+        let broken = path.with_extension("broken");
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&broken, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(restore(&client, &broken, None, true).await.is_err());
+        assert_eq!(client.count("kb", None).await.unwrap(), 1);
+        assert!(client
+            .collection_info("kb-restoring")
+            .await
+            .unwrap()
+            .is_none());
+        restore(&client, &path, None, true).await.unwrap();
+        assert_eq!(client.count("kb", None).await.unwrap(), 1);
+        for aside in ["kb-restoring", "kb-replaced"] {
+            assert!(client.collection_info(aside).await.unwrap().is_none());
+        }
+        std::fs::remove_file(&broken).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let info = client.collection_info("copy").await.unwrap().unwrap();
+        assert_eq!((info.config, info.points), (config, 1));
+        let (points, _) = client.scroll_page("copy", None, None, true).await.unwrap();
+        assert_eq!(points, vec![point]);
+
+        let line = r#"{"id":7,"vector":[1.0],"payload":{}}"#;
+        let point: BackupPoint = serde_json::from_str(line).unwrap();
+        assert_eq!(point.id, BackupId::Num(7));
+        assert_eq!(VectorPoint::from(point).id, "7");
+    }
+}
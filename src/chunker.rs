@@ -0,0 +1,241 @@
+use crate::ai::TokenEncoder;
+
+/// A heading which opens a new chunk, unless the current one holds fewer tokens than a quarter of
+/// the chunk size.
+const HEADING_BREAK_RATIO: usize = 4;
+
+/// Bounds of the chunks a document is split into before embedding.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkConfig {
+    /// Maximal number of tokens of a chunk.
+    pub max_tokens: usize,
+    /// Number of tokens at the end of a chunk which are repeated at the start of the next one.
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 512,
+            overlap_tokens: 64,
+        }
+    }
+}
+
+/// A paragraph, or a part of a paragraph too long to fit into one chunk.
+struct Unit {
+    text: String,
+    tokens: usize,
+    /// The Markdown heading of the section the unit belongs to.
+    heading: Option<String>,
+    starts_section: bool,
+}
+
+/// Split `content` into overlapping chunks of at most `config.max_tokens` tokens. Chunks are cut
+/// between paragraphs where possible, and Markdown headings start a new chunk. A chunk which
+/// continues a section starts with the heading of that section.
+pub fn chunk_text(encoder: &TokenEncoder, content: &str, config: ChunkConfig) -> Vec<String> {
+    let count = |text: &str| encoder.0.encode_with_special_tokens(text).len();
+    let units = split_units(content, config.max_tokens, &count);
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<&Unit> = Vec::new();
+    let mut tokens = 0;
+    for unit in units.iter() {
+        let heading_break =
+            unit.starts_section && tokens * HEADING_BREAK_RATIO >= config.max_tokens;
+        if !current.is_empty() && (heading_break || tokens + unit.tokens > config.max_tokens) {
+            chunks.push(join_units(&current));
+
+            // A continued section is repeated at the start of the chunk.
+            let heading_tokens = match (&unit.heading, unit.starts_section) {
+                (Some(heading), false) => count(heading) + 1,
+                _ => 0,
+            };
+
+            // Carry the tail of the chunk over, unless a new section starts.
+            let mut overlap = Vec::new();
+            let mut overlap_tokens = 0;
+            if !unit.starts_section {
+                for previous in current.iter().rev() {
+                    if previous.starts_section
+                        || overlap_tokens + previous.tokens > config.overlap_tokens
+                        || heading_tokens + overlap_tokens + previous.tokens + unit.tokens
+                            > config.max_tokens
+                    {
+                        break;
+                    }
+                    overlap_tokens += previous.tokens;
+                    overlap.insert(0, *previous);
+                }
+            }
+            current = overlap;
+            tokens = heading_tokens + overlap_tokens;
+        }
+
+        current.push(unit);
+        tokens += unit.tokens;
+    }
+    if !current.is_empty() {
+        chunks.push(join_units(&current));
+    }
+
+    chunks
+}
+
+fn join_units(units: &[&Unit]) -> String {
+    let texts: Vec<&str> = units.iter().map(|x| x.text.as_str()).collect();
+    let text = texts.join("\n\n");
+    match units[0].heading.as_ref() {
+        Some(heading) if !units[0].starts_section => format!("{}\n\n{}", heading, text),
+        _ => text,
+    }
+}
+
+fn is_heading(line: &str) -> bool {
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    (1..=6).contains(&hashes) && line[hashes..].starts_with(' ')
+}
+
+/// Break `content` into paragraphs, and paragraphs longer than `max_tokens` into pieces of
+/// whole words. Headings outside of code blocks start a new section.
+fn split_units(content: &str, max_tokens: usize, count: &dyn Fn(&str) -> usize) -> Vec<Unit> {
+    let mut units = Vec::new();
+    let mut heading: Option<String> = None;
+    let mut starts_section = false;
+    let mut in_fence = false;
+    let mut paragraph: Vec<&str> = Vec::new();
+
+    let mut flush = |paragraph: &mut Vec<&str>, heading: &Option<String>, starts: &mut bool| {
+        let text = paragraph.join("\n");
+        paragraph.clear();
+        if text.trim().is_empty() {
+            return;
+        }
+        for text in split_long(text.trim(), max_tokens.saturating_sub(1), count) {
+            units.push(Unit {
+                // One more for the separator to the next unit.
+                tokens: count(&text) + 1,
+                text,
+                heading: heading.clone(),
+                starts_section: *starts,
+            });
+            *starts = false;
+        }
+    };
+
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+        if !in_fence && is_heading(line) {
+            flush(&mut paragraph, &heading, &mut starts_section);
+            heading = Some(line.trim().to_string());
+            starts_section = true;
+        }
+        if !in_fence && line.trim().is_empty() {
+            flush(&mut paragraph, &heading, &mut starts_section);
+        } else {
+            paragraph.push(line);
+        }
+    }
+    flush(&mut paragraph, &heading, &mut starts_section);
+
+    units
+}
+
+/// Split `text` on whitespace into pieces of at most `max_tokens` tokens.
+fn split_long(text: &str, max_tokens: usize, count: &dyn Fn(&str) -> usize) -> Vec<String> {
+    if count(text) <= max_tokens {
+        return vec![text.to_string()];
+    }
+
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut tokens = 0;
+    for word in text.split_whitespace() {
+        let word_tokens = count(&format!(" {}", word));
+        if !piece.is_empty() && tokens + word_tokens > max_tokens {
+            pieces.push(std::mem::take(&mut piece));
+            tokens = 0;
+        }
+        if !piece.is_empty() {
+            piece.push(' ');
+        }
+        piece.push_str(word);
+        tokens += word_tokens;
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+/// Join two chunks of a document, dropping the text the second one repeats from the first.
+pub fn merge_chunks(first: &str, second: &str) -> String {
+    let overlap = (1..=first.len().min(second.len()))
+        .rev()
+        .filter(|&len| second.is_char_boundary(len) && first.is_char_boundary(first.len() - len))
+        .find(|&len| first.ends_with(&second[..len]));
+    match overlap {
+        Some(len) => format!("{}{}", first, &second[len..]),
+        None => format!("{}\n\n{}", first, second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_text, merge_chunks, ChunkConfig};
+    use crate::ai::TokenEncoder;
+
+    #[test]
+    fn test_chunks_are_bounded_and_overlap() {
+        let encoder = TokenEncoder::new().unwrap();
+        let content: Vec<String> = (0..40)
+            .map(|i| format!("Paragraph number {} talks about a topic.", i))
+            .collect();
+        let config = ChunkConfig {
+            max_tokens: 50,
+            overlap_tokens: 12,
+        };
+        let chunks = chunk_text(&encoder, &content.join("\n\n"), config);
+
+        assert!(chunks.len() > 1);
+        for chunk in chunks.iter() {
+            assert!(encoder.0.encode_with_special_tokens(chunk).len() <= 50);
+        }
+        for pair in chunks.windows(2) {
+            let last = pair[0].split("\n\n").last().unwrap();
+            assert!(pair[1].starts_with(last));
+        }
+    }
+
+    #[test]
+    fn test_chunks_follow_markdown_headings() {
+        let encoder = TokenEncoder::new().unwrap();
+        let body = "Some words about it. ".repeat(12);
+        let content = format!(
+            "# Install\n\n{}\n\n{}\n\n# Usage\n\n```\n# not a heading\n```\n\n{}",
+            body, body, body
+        );
+        let config = ChunkConfig {
+            max_tokens: 80,
+            overlap_tokens: 0,
+        };
+        let chunks = chunk_text(&encoder, &content, config);
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("# Install\n\nSome words"));
+        assert!(chunks[1].starts_with("# Install\n\nSome words"));
+        assert!(chunks[2].starts_with("# Usage\n\n```\n# not a heading\n```\n\nSome words"));
+    }
+
+    #[test]
+    fn test_merge_chunks() {
+        assert_eq!(
+            merge_chunks("a b c\n\nd e", "d e\n\nf"),
+            "a b c\n\nd e\n\nf"
+        );
+        assert_eq!(merge_chunks("a b", "c d"), "a b\n\nc d");
+    }
+}
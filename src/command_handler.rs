@@ -1,14 +1,33 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serenity::{prelude::GatewayIntents, Client};
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use structopt::StructOpt;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
-    ai::Openai,
-    conversation::ConversationCache,
+    ai::{ChatProvider, EmbeddingProvider, Openai, TokenEncoder, TokenEncoders},
+    backup,
+    chunker::ChunkConfig,
+    config::{BotConfig, ChannelSettings, ConfigHandle, TriggerMode, DEFAULT_SYSTEM_PROMPT},
+    conversation::{ConversationCache, ConversationScope, ConversationStore},
+    ingest::load_documents,
+    kb::{self, document_filter, PayloadCondition},
     knowledge_base::{clear_collection, query, upsert_knowledge, KnowledgeClient},
+    memory::MemoryMode,
+    models::{lookup, GenerationConfig, DEFAULT_CHAT_MODEL, DEFAULT_EMBEDDING_MODEL},
     msg_handler::Handler,
+    rate_limit::{RateLimitConfig, RateLimiter},
+    retrieval::{PassageCache, RetrievalConfig},
+    retry::{RetryPolicy, Retrying},
+    sqlite_store::SqliteConversationStore,
+    sqlite_vector_store::SqliteVectorStore,
+    usage::{format_report, write_csv, UsageGroup, UsageStore},
 };
 
 #[derive(StructOpt, Debug)]
@@ -17,9 +36,13 @@ use crate::{
     about = "A tool that enables the creation of a Discord AI bot service utilizing the power of GPT-3.5"
 )]
 pub struct DiscordAiBot {
-    /// Openai api key
-    #[structopt(name = "openai-api-key", env = "OPENAI_API_KEY")]
-    openai_api_key: String,
+    /// Openai api key, needed by the commands which call the api
+    #[structopt(
+        name = "openai-api-key",
+        env = "OPENAI_API_KEY",
+        hide_env_values = true
+    )]
+    openai_api_key: Option<String>,
 
     #[structopt(
         name = "qdrant-rpc-url",
@@ -28,6 +51,30 @@ pub struct DiscordAiBot {
     )]
     qdrant_grpc_url: String,
 
+    /// Where the knowledge bases are kept: "qdrant", or "sqlite" to run without a server
+    #[structopt(long = "vector-store", default_value = "qdrant")]
+    vector_store: VectorBackend,
+
+    /// SQLite database of the "sqlite" vector store
+    #[structopt(long = "vector-db", default_value = "knowledge.db", parse(from_os_str))]
+    vector_db: PathBuf,
+
+    /// Base url of an OpenAI compatible api (e.g. http://localhost:8080/v1), uses OpenAI if absent
+    #[structopt(long = "api-base", env = "OPENAI_API_BASE")]
+    api_base: Option<String>,
+
+    /// Chat model of the channels which don't configure their own
+    #[structopt(long = "chat-model", default_value = DEFAULT_CHAT_MODEL)]
+    chat_model: String,
+
+    /// Embedding model requested from the api
+    #[structopt(long = "embedding-model", default_value = DEFAULT_EMBEDDING_MODEL)]
+    embedding_model: String,
+
+    /// Seconds a failed request to the api is retried for, 0 to never retry
+    #[structopt(long = "retry-timeout", default_value = "60")]
+    retry_timeout: u64,
+
     #[structopt(subcommand)]
     cmd: Opt,
 }
@@ -40,8 +87,86 @@ pub enum Opt {
         /// Discord bot token
         #[structopt(name = "discord-bot-token", env = "DISCORD_TOKEN")]
         discord_bot_token: String,
+        /// Collection of the channels which don't configure their own
         #[structopt(name = "collection-name")]
         collection_name: String,
+        /// TOML file with settings per guild and channel
+        #[structopt(long = "config", parse(from_os_str))]
+        config: Option<PathBuf>,
+        /// Which messages are answered: "mention", "all" or "slash", unless configured per
+        /// guild or channel
+        #[structopt(long = "trigger", default_value = "mention")]
+        trigger: TriggerMode,
+        /// Progressively edit the reply while the answer is generated
+        #[structopt(long = "stream")]
+        stream: bool,
+        /// Where conversations are kept: "lru" in memory, or "sqlite" to survive restarts
+        #[structopt(long = "conversation-store", default_value = "lru")]
+        conversation_store: ConversationBackend,
+        /// SQLite database of the "sqlite" conversation store
+        #[structopt(
+            long = "conversation-db",
+            default_value = "conversations.db",
+            parse(from_os_str)
+        )]
+        conversation_db: PathBuf,
+        /// What a conversation spans: "user", "user-channel", "channel" or "thread", where
+        /// threads are shared by their members and other channels are kept per user
+        #[structopt(long = "conversation-scope", default_value = "user")]
+        conversation_scope: ConversationScope,
+        /// How long conversations are kept within the context: "window" drops their oldest
+        /// messages, "summary" condenses them into a summary
+        #[structopt(long = "memory", default_value = "window")]
+        memory: MemoryMode,
+        /// Tokens of the history of a conversation beyond which its oldest messages are
+        /// summarized, with "--memory summary"
+        #[structopt(long = "summary-tokens", default_value = "1000")]
+        summary_tokens: usize,
+        /// Maximal number of knowledge passages retrieved for a question
+        #[structopt(long = "knowledge-top-k", default_value = "5")]
+        knowledge_top_k: usize,
+        /// Minimal similarity of a knowledge passage to the question, unless configured per
+        /// guild or channel
+        #[structopt(long = "score-threshold", default_value = "0.78")]
+        score_threshold: f32,
+        /// Maximal number of tokens of the knowledge passages given with a question
+        #[structopt(long = "knowledge-tokens", default_value = "1500")]
+        knowledge_tokens: usize,
+        /// Sampling temperature between 0 and 2, unless configured per guild or channel
+        #[structopt(long = "temperature")]
+        temperature: Option<f32>,
+        /// Nucleus sampling probability mass between 0 and 1, unless configured per guild or
+        /// channel
+        #[structopt(long = "top-p")]
+        top_p: Option<f32>,
+        /// Maximal number of tokens of an answer, unless configured per guild or channel.
+        /// Without it 512 tokens of the context are kept free for the answer
+        #[structopt(long = "max-tokens")]
+        max_tokens: Option<u16>,
+        /// Sequence the model stops generating at, up to 4 times
+        #[structopt(long = "stop", number_of_values = 1)]
+        stop: Vec<String>,
+        /// Questions a user may ask per minute, 0 for no limit
+        #[structopt(long = "user-rate-limit", default_value = "5")]
+        user_rate_limit: u32,
+        /// Questions which may be asked in a channel per minute, 0 for no limit
+        #[structopt(long = "channel-rate-limit", default_value = "20")]
+        channel_rate_limit: u32,
+        /// Questions which may be asked in a guild per minute, 0 for no limit
+        #[structopt(long = "guild-rate-limit", default_value = "60")]
+        guild_rate_limit: u32,
+        /// Tokens the questions of a user may consume per day, 0 for no quota
+        #[structopt(long = "user-daily-tokens", default_value = "0")]
+        user_daily_tokens: u64,
+        /// Tokens the questions of a guild may consume per day, 0 for no quota
+        #[structopt(long = "guild-daily-tokens", default_value = "0")]
+        guild_daily_tokens: u64,
+        /// JSON file keeping the rate limit counters and quotas across restarts
+        #[structopt(long = "rate-limit-state", parse(from_os_str))]
+        rate_limit_state: Option<PathBuf>,
+        /// SQLite database keeping the tokens consumed by every question
+        #[structopt(long = "usage-db", default_value = "usage.db", parse(from_os_str))]
+        usage_db: PathBuf,
     },
 
     /// Upsert knowledge into a knowledge base
@@ -49,9 +174,29 @@ pub enum Opt {
         /// Collection name
         collection: String,
 
-        /// JSON file to update knowledge base
-        #[structopt(name = "FILE", parse(from_os_str))]
-        file: PathBuf,
+        /// A .json, .jsonl, .md or .txt file, or a directory of them, to update knowledge base
+        #[structopt(name = "PATH", parse(from_os_str))]
+        path: PathBuf,
+
+        /// Directory the ids of files without an id or url are relative to
+        #[structopt(long = "root", default_value = ".", parse(from_os_str))]
+        root: PathBuf,
+
+        /// Maximal number of tokens of a chunk of a document
+        #[structopt(long = "chunk-tokens", default_value = "512")]
+        chunk_tokens: usize,
+
+        /// Number of tokens repeated between consecutive chunks of a document
+        #[structopt(long = "chunk-overlap", default_value = "64")]
+        chunk_overlap: usize,
+
+        /// Skip documents which didn't change since the last update
+        #[structopt(long)]
+        incremental: bool,
+
+        /// SQLite database keeping the tokens consumed by embedding the knowledge
+        #[structopt(long = "usage-db", default_value = "usage.db", parse(from_os_str))]
+        usage_db: PathBuf,
     },
 
     /// Query knowledge base
@@ -68,34 +213,330 @@ pub enum Opt {
         /// Collection name
         collection: String,
     },
+
+    /// Report the tokens consumed and their estimated cost
+    Usage {
+        /// SQLite database keeping the tokens consumed by every question
+        #[structopt(long = "usage-db", default_value = "usage.db", parse(from_os_str))]
+        usage_db: PathBuf,
+
+        /// What to sum up by, any of "day", "guild", "channel", "user", "model" and "kind",
+        /// separated by commas
+        #[structopt(long = "group-by", default_value = "day", use_delimiter = true)]
+        group_by: Vec<UsageGroup>,
+
+        /// Only report the usage of the last days
+        #[structopt(long)]
+        days: Option<u64>,
+
+        /// Write the report as CSV into a file instead of printing it
+        #[structopt(long, parse(from_os_str))]
+        csv: Option<PathBuf>,
+    },
+
+    /// Inspect and curate the documents of a knowledge base
+    Kb(KbCommand),
+}
+
+#[derive(StructOpt, Debug)]
+pub enum KbCommand {
+    /// List the documents with their titles, urls and numbers of chunks
+    List {
+        /// Collection name
+        collection: String,
+
+        /// Page of the list, counted from 1
+        #[structopt(long, default_value = "1")]
+        page: usize,
+
+        /// Documents per page
+        #[structopt(long = "page-size", default_value = "20")]
+        page_size: usize,
+    },
+
+    /// Print a document
+    Show {
+        /// Collection name
+        collection: String,
+
+        /// Id or url of the document
+        document: String,
+    },
+
+    /// Delete documents
+    Delete {
+        /// Collection name
+        collection: String,
+
+        /// Id of a document to delete, repeat for more
+        #[structopt(long = "id", number_of_values = 1)]
+        ids: Vec<String>,
+
+        /// Url of a document to delete, repeat for more
+        #[structopt(long = "url", number_of_values = 1)]
+        urls: Vec<String>,
+
+        /// Only delete chunks whose payload field has a value, like "title=FAQ", repeat for more
+        #[structopt(long = "filter", number_of_values = 1)]
+        filters: Vec<PayloadCondition>,
+
+        /// Only count the chunks which would be deleted
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+
+    /// Print the number of documents, chunks and tokens
+    Stats {
+        /// Collection name
+        collection: String,
+    },
+
+    /// Export the documents as JSON lines, which update reads back
+    Export {
+        /// Collection name
+        collection: String,
+
+        /// File to write, stdout if absent
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Back up the points with their vectors into a compressed file
+    Backup {
+        /// Collection name
+        collection: String,
+
+        /// File to write
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Recreate a collection from a backup without embedding it again
+    Restore {
+        /// Backup file
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Collection to restore into, the backed up collection if absent
+        #[structopt(long)]
+        collection: Option<String>,
+
+        /// Replace the collection if it exists
+        #[structopt(long)]
+        replace: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConversationBackend {
+    Lru,
+    Sqlite,
+}
+
+impl FromStr for ConversationBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("Unknown conversation store: {}", s)),
+        }
+    }
+}
+
+type Providers = (Arc<dyn ChatProvider>, Arc<dyn EmbeddingProvider>);
+
+/// Use OpenAI, or an OpenAI compatible server when `api_base` is given, retrying failed
+/// requests according to `policy`.
+fn build_providers(
+    api_base: Option<&str>,
+    api_key: &str,
+    embedding_model: &str,
+    policy: RetryPolicy,
+) -> Providers {
+    if let Some(api_base) = api_base {
+        info!("Using OpenAI compatible api at {}", api_base);
+    }
+    let provider = Arc::new(Retrying {
+        inner: Openai::new(api_base, api_key, embedding_model),
+        policy,
+    });
+    (provider.clone(), provider)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VectorBackend {
+    Qdrant,
+    Sqlite,
+}
+
+impl FromStr for VectorBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qdrant" => Ok(Self::Qdrant),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("Unknown vector store: {}", s)),
+        }
+    }
+}
+
+async fn open_knowledge_client(
+    backend: VectorBackend,
+    qdrant_url: &str,
+    vector_db: &Path,
+) -> Result<KnowledgeClient> {
+    match backend {
+        VectorBackend::Qdrant => KnowledgeClient::qdrant(qdrant_url).await,
+        VectorBackend::Sqlite => {
+            info!("Keeping knowledge in {:?}", vector_db);
+            let store = SqliteVectorStore::open(vector_db)?;
+            Ok(KnowledgeClient::new(Arc::new(store)))
+        }
+    }
 }
 
 pub async fn execute() -> Result<()> {
     let DiscordAiBot {
         qdrant_grpc_url,
+        vector_store,
+        vector_db,
         openai_api_key,
+        api_base,
+        chat_model,
+        embedding_model,
+        retry_timeout,
         cmd,
     } = DiscordAiBot::from_args();
+    let policy = RetryPolicy {
+        max_elapsed: Duration::from_secs(retry_timeout),
+        ..Default::default()
+    };
+    let providers = || -> Result<Providers> {
+        let api_key = openai_api_key
+            .as_deref()
+            .ok_or_else(|| anyhow!("Give the openai api key by OPENAI_API_KEY"))?;
+        Ok(build_providers(
+            api_base.as_deref(),
+            api_key,
+            &embedding_model,
+            policy,
+        ))
+    };
+    let knowledge_client = || open_knowledge_client(vector_store, &qdrant_grpc_url, &vector_db);
 
     match cmd {
         Opt::Start {
             discord_bot_token,
             collection_name,
+            config,
+            trigger,
+            stream,
+            conversation_store,
+            conversation_db,
+            conversation_scope,
+            memory,
+            summary_tokens,
+            knowledge_top_k,
+            score_threshold,
+            knowledge_tokens,
+            temperature,
+            top_p,
+            max_tokens,
+            stop,
+            user_rate_limit,
+            channel_rate_limit,
+            guild_rate_limit,
+            user_daily_tokens,
+            guild_daily_tokens,
+            rate_limit_state,
+            usage_db,
         } => {
+            let (chat_provider, embedding_provider) = providers()?;
             // Set gateway intents, which decides what events the bot will be notified about
             let intents = GatewayIntents::GUILD_MESSAGES
                 | GatewayIntents::DIRECT_MESSAGES
                 | GatewayIntents::MESSAGE_CONTENT;
 
-            let openai_client = Openai::new(&openai_api_key)?;
-            let conversation_cache = ConversationCache::default();
-            let qdrant_client = KnowledgeClient::new(&qdrant_grpc_url).await?;
+            let conversation_store: Box<dyn ConversationStore> = match conversation_store {
+                ConversationBackend::Lru => Box::<ConversationCache>::default(),
+                ConversationBackend::Sqlite => {
+                    info!("Persisting conversations in {:?}", conversation_db);
+                    Box::new(SqliteConversationStore::open(&conversation_db)?)
+                }
+            };
+            let settings = ChannelSettings {
+                collection: collection_name,
+                system_prompt: DEFAULT_SYSTEM_PROMPT.into(),
+                model: chat_model,
+                score_threshold,
+                allowed_channels: None,
+                trigger,
+                retrieval: RetrievalConfig {
+                    top_k: knowledge_top_k,
+                    token_budget: knowledge_tokens,
+                },
+                generation: GenerationConfig {
+                    temperature,
+                    top_p,
+                    max_tokens,
+                    stop,
+                },
+            };
+            settings.generation.validate()?;
+            let config = match config {
+                Some(path) => {
+                    info!("Loading config {:?}", path);
+                    let config = ConfigHandle::new(BotConfig::load(&path)?, Some(path));
+                    config.watch();
+                    config
+                }
+                None => ConfigHandle::new(BotConfig::default(), None),
+            };
+            if lookup(&settings.model, &config.get().models).is_none() {
+                warn!(
+                    "Unknown chat model {}, assuming a context of {} tokens. Describe it under [models] in the config",
+                    settings.model,
+                    config.get().model_spec(&settings.model).context_window
+                );
+            }
+            let rate_limit = RateLimitConfig {
+                user_per_minute: user_rate_limit,
+                channel_per_minute: channel_rate_limit,
+                guild_per_minute: guild_rate_limit,
+                user_daily_tokens,
+                guild_daily_tokens,
+            };
+            let rate_limiter = match rate_limit_state {
+                Some(path) => {
+                    info!("Persisting rate limits in {:?}", path);
+                    RateLimiter::open(rate_limit, &path)?
+                }
+                None => RateLimiter::new(rate_limit),
+            };
+            info!("Keeping token usage in {:?}", usage_db);
+            let usage_store = UsageStore::open(&usage_db)?;
+            let knowledge_client = knowledge_client().await?;
+            knowledge_client.watch_health();
             let mut client = Client::builder(&discord_bot_token, intents)
                 .event_handler(Handler {
-                    openai_client,
-                    conversation_cache,
-                    knowledge_client: qdrant_client,
-                    collection_name,
+                    chat_provider,
+                    embedding_provider,
+                    token_encoder: TokenEncoder::new()?,
+                    token_encoders: TokenEncoders::default(),
+                    conversation_store,
+                    conversation_scope,
+                    memory,
+                    summary_threshold: summary_tokens,
+                    knowledge_client,
+                    settings,
+                    config,
+                    last_passages: PassageCache::default(),
+                    stream_replies: stream,
+                    rate_limiter,
+                    usage_store,
                 })
                 .await
                 .expect("Err creating discord bot client");
@@ -104,23 +545,113 @@ pub async fn execute() -> Result<()> {
                 error!("Client error: {:?}", why);
             }
         }
-        Opt::Update { collection, file } => {
-            info!("Upserting knowledge into a knowledge base: {:?}", file);
-            upsert_knowledge(&qdrant_grpc_url, file, &collection).await?;
+        Opt::Update {
+            collection,
+            path,
+            root,
+            chunk_tokens,
+            chunk_overlap,
+            incremental,
+            usage_db,
+        } => {
+            let (_, embedding_provider) = providers()?;
+            info!("Upserting knowledge into a knowledge base: {:?}", path);
+            let chunk_config = ChunkConfig {
+                max_tokens: chunk_tokens,
+                overlap_tokens: chunk_overlap,
+            };
+            upsert_knowledge(
+                &knowledge_client().await?,
+                embedding_provider.as_ref(),
+                &UsageStore::open(&usage_db)?,
+                load_documents(&path, &root)?,
+                &collection,
+                chunk_config,
+                incremental,
+            )
+            .await?;
         }
         Opt::Query {
             collection,
             question,
         } => {
+            let (_, embedding_provider) = providers()?;
             info!(
                 "Querying related fact from {:?}: {:?}",
                 collection, question
             );
-            query(&qdrant_grpc_url, &question, &collection).await?;
+            query(
+                &knowledge_client().await?,
+                embedding_provider.as_ref(),
+                &question,
+                &collection,
+            )
+            .await?;
         }
         Opt::Clear { collection } => {
             info!("Clearing collection: {:?}", collection);
-            clear_collection(&qdrant_grpc_url, &collection).await?;
+            clear_collection(&knowledge_client().await?, &collection).await?;
+        }
+        Opt::Usage {
+            usage_db,
+            group_by,
+            days,
+            csv,
+        } => {
+            let store = UsageStore::open(&usage_db)?;
+            let since = match days {
+                Some(days) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                    now.saturating_sub(days * 24 * 60 * 60)
+                }
+                None => 0,
+            };
+            let rows = store.report(&group_by, since)?;
+            match csv {
+                Some(path) => {
+                    write_csv(&group_by, &rows, File::create(&path)?)?;
+                    info!("Wrote {} rows to {:?}", rows.len(), path);
+                }
+                None => println!("{}", format_report(&group_by, &rows)),
+            }
+        }
+        Opt::Kb(command) => {
+            let knowledge_client = knowledge_client().await?;
+            match command {
+                KbCommand::List {
+                    collection,
+                    page,
+                    page_size,
+                } => kb::list(&knowledge_client, &collection, page, page_size.max(1)).await?,
+                KbCommand::Show {
+                    collection,
+                    document,
+                } => kb::show(&knowledge_client, &collection, &document).await?,
+                KbCommand::Delete {
+                    collection,
+                    ids,
+                    urls,
+                    filters,
+                    dry_run,
+                } => {
+                    let filter = document_filter(&ids, &urls, &filters)?;
+                    kb::delete(&knowledge_client, &collection, filter, dry_run).await?
+                }
+                KbCommand::Stats { collection } => {
+                    kb::stats(&knowledge_client, &collection).await?
+                }
+                KbCommand::Export { collection, output } => {
+                    kb::export(&knowledge_client, &collection, output).await?
+                }
+                KbCommand::Backup { collection, file } => {
+                    backup::backup(&knowledge_client, &collection, &file).await?
+                }
+                KbCommand::Restore {
+                    file,
+                    collection,
+                    replace,
+                } => backup::restore(&knowledge_client, &file, collection, replace).await?,
+            }
         }
     }
     Ok(())
@@ -0,0 +1,619 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::{Debug, Display},
+    fs,
+    hash::Hash,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serenity::model::prelude::{ChannelId, GuildId};
+use tracing::{error, info, warn};
+
+use crate::{
+    models::{lookup, GenerationConfig, ModelSpec},
+    retrieval::RetrievalConfig,
+};
+
+/// Interval between two checks whether the config file changed.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// System prompt of channels which don't configure their own.
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant.";
+
+/// Appended to every system prompt, so the format of the questions and the citations of the
+/// knowledge passages stay the same whatever prompt is configured.
+pub const KNOWLEDGE_PROMPT: &str = "I will ask with format like this:
+        Question: {text}
+        Knowledge: {text}
+        You should answer question after the 'Question'.
+        And there may be related knowledge after knowledge you could refer to, given as
+        numbered passages like '[1] title (url)'. Cite the passages you use by their number in
+        square brackets, like [1]. ";
+
+/// Which messages the bot answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerMode {
+    /// Messages which mention the bot, reply to it, or are sent in a direct message.
+    Mention,
+    /// Every message.
+    All,
+    /// Slash commands only.
+    Slash,
+}
+
+impl FromStr for TriggerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mention" => Ok(Self::Mention),
+            "all" => Ok(Self::All),
+            "slash" => Ok(Self::Slash),
+            _ => Err(format!("Unknown trigger mode: {}", s)),
+        }
+    }
+}
+
+/// Settings of a scope of the config file. Settings which are left out are inherited from the
+/// enclosing scope.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScopeConfig {
+    pub collection: Option<String>,
+    pub system_prompt: Option<String>,
+    pub model: Option<String>,
+    pub score_threshold: Option<f32>,
+    /// Channels the bot answers in. Direct messages are always answered.
+    pub allowed_channels: Option<Vec<u64>>,
+    pub trigger: Option<TriggerMode>,
+    pub knowledge_top_k: Option<usize>,
+    pub knowledge_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u16>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl ScopeConfig {
+    /// The settings which differ from `old`, like `model: None -> Some("gpt-4")`.
+    fn diff(&self, old: &ScopeConfig) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: &dyn Debug, new: &dyn Debug| {
+            let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare("collection", &old.collection, &self.collection);
+        compare("system_prompt", &old.system_prompt, &self.system_prompt);
+        compare("model", &old.model, &self.model);
+        compare(
+            "score_threshold",
+            &old.score_threshold,
+            &self.score_threshold,
+        );
+        compare(
+            "allowed_channels",
+            &old.allowed_channels,
+            &self.allowed_channels,
+        );
+        compare("trigger", &old.trigger, &self.trigger);
+        compare(
+            "knowledge_top_k",
+            &old.knowledge_top_k,
+            &self.knowledge_top_k,
+        );
+        compare(
+            "knowledge_tokens",
+            &old.knowledge_tokens,
+            &self.knowledge_tokens,
+        );
+        compare("temperature", &old.temperature, &self.temperature);
+        compare("top_p", &old.top_p, &self.top_p);
+        compare("max_tokens", &old.max_tokens, &self.max_tokens);
+        compare("stop", &old.stop, &self.stop);
+        changes
+    }
+}
+
+/// The settings which apply to a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSettings {
+    pub collection: String,
+    pub system_prompt: String,
+    pub model: String,
+    pub score_threshold: f32,
+    pub allowed_channels: Option<Vec<ChannelId>>,
+    pub trigger: TriggerMode,
+    pub retrieval: RetrievalConfig,
+    pub generation: GenerationConfig,
+}
+
+impl ChannelSettings {
+    fn apply(&mut self, scope: &ScopeConfig) {
+        if let Some(collection) = &scope.collection {
+            self.collection = collection.clone();
+        }
+        if let Some(system_prompt) = &scope.system_prompt {
+            self.system_prompt = system_prompt.clone();
+        }
+        if let Some(model) = &scope.model {
+            self.model = model.clone();
+        }
+        if let Some(score_threshold) = scope.score_threshold {
+            self.score_threshold = score_threshold;
+        }
+        if let Some(allowed_channels) = &scope.allowed_channels {
+            self.allowed_channels = Some(allowed_channels.iter().map(|&x| ChannelId(x)).collect());
+        }
+        if let Some(trigger) = scope.trigger {
+            self.trigger = trigger;
+        }
+        if let Some(top_k) = scope.knowledge_top_k {
+            self.retrieval.top_k = top_k;
+        }
+        if let Some(token_budget) = scope.knowledge_tokens {
+            self.retrieval.token_budget = token_budget;
+        }
+        if let Some(temperature) = scope.temperature {
+            self.generation.temperature = Some(temperature);
+        }
+        if let Some(top_p) = scope.top_p {
+            self.generation.top_p = Some(top_p);
+        }
+        if let Some(max_tokens) = scope.max_tokens {
+            self.generation.max_tokens = Some(max_tokens);
+        }
+        if let Some(stop) = &scope.stop {
+            self.generation.stop = stop.clone();
+        }
+    }
+
+    /// The configured system prompt followed by `KNOWLEDGE_PROMPT`.
+    pub fn system_message(&self) -> String {
+        format!("{}\n{}", self.system_prompt.trim_end(), KNOWLEDGE_PROMPT)
+    }
+
+    /// Whether the bot answers in `channel_id`. Direct messages are always answered.
+    pub fn allows(&self, guild_id: Option<GuildId>, channel_id: ChannelId) -> bool {
+        match (&self.allowed_channels, guild_id) {
+            (Some(allowed), Some(_)) => allowed.contains(&channel_id),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    default: ScopeConfig,
+    #[serde(default)]
+    guilds: HashMap<String, ScopeConfig>,
+    #[serde(default)]
+    channels: HashMap<String, ScopeConfig>,
+    #[serde(default)]
+    models: HashMap<String, ModelSpec>,
+}
+
+/// Settings per guild and channel, read from a TOML file like:
+///
+/// ```toml
+/// [default]
+/// trigger = "mention"
+///
+/// [guilds.123456789]
+/// collection = "docs"
+/// # Followed by `KNOWLEDGE_PROMPT`, which asks for citations of the knowledge passages.
+/// system_prompt = "You answer questions about our product."
+///
+/// [channels.987654321]
+/// model = "gpt-4"
+/// score_threshold = 0.8
+///
+/// [models."llama-2-13b-chat"]
+/// context_window = 4096
+/// ```
+///
+/// Channel settings override the settings of their guild, which override the defaults. Models
+/// describe chat models which aren't known to the bot, or override known ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BotConfig {
+    pub default: ScopeConfig,
+    pub guilds: HashMap<GuildId, ScopeConfig>,
+    pub channels: HashMap<ChannelId, ScopeConfig>,
+    pub models: HashMap<String, ModelSpec>,
+}
+
+impl BotConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Reading config {:?}", path))?;
+        text.parse()
+            .with_context(|| format!("Parsing config {:?}", path))
+    }
+
+    /// The settings which apply to messages in `channel_id`, starting from `base`.
+    pub fn resolve(
+        &self,
+        base: &ChannelSettings,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+    ) -> ChannelSettings {
+        let mut settings = base.clone();
+        settings.apply(&self.default);
+        if let Some(guild) = guild_id.and_then(|x| self.guilds.get(&x)) {
+            settings.apply(guild);
+        }
+        if let Some(channel) = self.channels.get(&channel_id) {
+            settings.apply(channel);
+        }
+        settings
+    }
+
+    /// The spec of `model`, the default spec if the model is neither configured nor known.
+    pub fn model_spec(&self, model: &str) -> ModelSpec {
+        lookup(model, &self.models).unwrap_or_default()
+    }
+
+    /// The changes from `old` to this config, one line per changed setting.
+    pub fn diff(&self, old: &BotConfig) -> Vec<String> {
+        let mut changes: Vec<String> = self
+            .default
+            .diff(&old.default)
+            .into_iter()
+            .map(|x| format!("default.{}", x))
+            .collect();
+        changes.append(&mut diff_scopes("guilds", &old.guilds, &self.guilds));
+        changes.append(&mut diff_scopes("channels", &old.channels, &self.channels));
+        let models: BTreeSet<&String> = old.models.keys().chain(self.models.keys()).collect();
+        for model in models {
+            match (old.models.get(model), self.models.get(model)) {
+                (None, Some(_)) => changes.push(format!("models.{} added", model)),
+                (Some(_), None) => changes.push(format!("models.{} removed", model)),
+                (Some(old), Some(new)) if old != new => {
+                    changes.push(format!("models.{}: {:?} -> {:?}", model, old, new))
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+fn diff_scopes<K: Hash + Eq + Display + Ord>(
+    table: &str,
+    old: &HashMap<K, ScopeConfig>,
+    new: &HashMap<K, ScopeConfig>,
+) -> Vec<String> {
+    let ids: BTreeSet<&K> = old.keys().chain(new.keys()).collect();
+    let mut changes = Vec::new();
+    for id in ids {
+        match (old.get(id), new.get(id)) {
+            (None, Some(_)) => changes.push(format!("{}.{} added", table, id)),
+            (Some(_), None) => changes.push(format!("{}.{} removed", table, id)),
+            (Some(old), Some(new)) => changes.extend(
+                new.diff(old)
+                    .into_iter()
+                    .map(|x| format!("{}.{}.{}", table, id, x)),
+            ),
+            (None, None) => {}
+        }
+    }
+    changes
+}
+
+/// The config of a running bot, which is reloaded when its file changes or the process receives
+/// SIGHUP. A config which fails to load is logged and the previous config stays in effect.
+#[derive(Debug, Clone)]
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    current: Arc<RwLock<Arc<BotConfig>>>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: BotConfig, path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<BotConfig> {
+        match self.current.read() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Load the config file again, and swap it in if it's valid. Returns the changes.
+    pub fn reload(&self) -> Result<Vec<String>> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("No config file to reload"))?;
+        let config = BotConfig::load(path)?;
+        let changes = config.diff(&self.get());
+        match self.current.write() {
+            Ok(mut current) => *current = Arc::new(config),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(config),
+        }
+        Ok(changes)
+    }
+
+    fn reload_and_log(&self) {
+        match self.reload() {
+            Ok(changes) if changes.is_empty() => info!("Reloaded config, nothing changed"),
+            Ok(changes) => {
+                info!("Reloaded config, {} changes", changes.len());
+                for change in changes {
+                    info!("  {}", change);
+                }
+            }
+            Err(why) => error!("Reloading config failed, keeping the old one: {:?}", why),
+        }
+    }
+
+    /// Reload the config whenever its file changes or the process receives SIGHUP.
+    pub fn watch(&self) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let handle = self.clone();
+        // Listen before returning, so a SIGHUP is never missed or kills the process.
+        let mut listener = listen_hangup();
+        tokio::spawn(async move {
+            let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+            let mut last_modified: Option<SystemTime> = modified(&path);
+            let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = hangup(&mut listener) => info!("Received SIGHUP"),
+                    _ = interval.tick() => {
+                        let current = modified(&path);
+                        if current == last_modified {
+                            continue;
+                        }
+                        info!("Config {:?} changed", path);
+                    }
+                }
+                last_modified = modified(&path);
+                handle.reload_and_log();
+            }
+        });
+    }
+}
+
+/// Listener of SIGHUP, `None` if listening failed.
+#[cfg(unix)]
+type HangupListener = Option<tokio::signal::unix::Signal>;
+
+#[cfg(not(unix))]
+type HangupListener = ();
+
+/// Start listening to SIGHUP, which from then on doesn't terminate the process anymore.
+#[cfg(unix)]
+fn listen_hangup() -> HangupListener {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())
+        .map_err(|why| warn!("Listening to SIGHUP failed: {:?}", why))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn listen_hangup() -> HangupListener {}
+
+/// Wait for the next SIGHUP received by `listener`.
+#[cfg(unix)]
+async fn hangup(listener: &mut HangupListener) {
+    let received = match listener {
+        Some(signal) => signal.recv().await,
+        None => None,
+    };
+    if received.is_none() {
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangup(_: &mut HangupListener) {
+    std::future::pending::<()>().await
+}
+
+fn validate(scope: &str, config: &ScopeConfig) -> Result<()> {
+    if let Some(threshold) = config.score_threshold {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(anyhow!(
+                "{}: score_threshold {} is not between 0 and 1",
+                scope,
+                threshold
+            ));
+        }
+    }
+    for (name, value) in [
+        ("knowledge_top_k", config.knowledge_top_k),
+        ("knowledge_tokens", config.knowledge_tokens),
+    ] {
+        if value == Some(0) {
+            return Err(anyhow!("{}: {} is 0", scope, name));
+        }
+    }
+    for (name, value) in [
+        ("collection", &config.collection),
+        ("model", &config.model),
+        ("system_prompt", &config.system_prompt),
+    ] {
+        if value.as_ref().is_some_and(|x| x.trim().is_empty()) {
+            return Err(anyhow!("{}: {} is empty", scope, name));
+        }
+    }
+    let generation = GenerationConfig {
+        temperature: config.temperature,
+        top_p: config.top_p,
+        max_tokens: config.max_tokens,
+        stop: config.stop.clone().unwrap_or_default(),
+    };
+    generation
+        .validate()
+        .map_err(|why| anyhow!("{}: {}", scope, why))
+}
+
+fn parse_id(scope: &str, id: &str) -> Result<u64> {
+    id.parse()
+        .map_err(|_| anyhow!("{}: {:?} is not a discord id", scope, id))
+}
+
+impl FromStr for BotConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let file: ConfigFile = toml::from_str(s)?;
+        validate("default", &file.default)?;
+
+        let mut config = BotConfig {
+            default: file.default,
+            ..Default::default()
+        };
+        for (id, guild) in file.guilds {
+            let scope = format!("guilds.{}", id);
+            validate(&scope, &guild)?;
+            config.guilds.insert(GuildId(parse_id(&scope, &id)?), guild);
+        }
+        for (id, channel) in file.channels {
+            let scope = format!("channels.{}", id);
+            validate(&scope, &channel)?;
+            config
+                .channels
+                .insert(ChannelId(parse_id(&scope, &id)?), channel);
+        }
+        for (name, model) in file.models {
+            model
+                .validate()
+                .map_err(|why| anyhow!("models.{}: {}", name, why))?;
+            config.models.insert(name, model);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::prelude::{ChannelId, GuildId};
+
+    use super::{BotConfig, ChannelSettings, TriggerMode};
+
+    #[test]
+    fn test_resolve_config() {
+        let config: BotConfig = r#"
+            [default]
+            trigger = "all"
+
+            [guilds.1]
+            collection = "guild"
+            system_prompt = "You answer questions about our product."
+            allowed_channels = [10, 11]
+
+            [channels.11]
+            model = "local"
+            score_threshold = 0.5
+            max_tokens = 300
+            stop = ["Question:"]
+
+            [models.local]
+            context_window = 2048
+            tokens_per_name = -1
+        "#
+        .parse()
+        .unwrap();
+        let base = ChannelSettings {
+            collection: "base".into(),
+            system_prompt: "prompt".into(),
+            model: "gpt-3.5-turbo".into(),
+            score_threshold: 0.78,
+            allowed_channels: None,
+            trigger: TriggerMode::Mention,
+            retrieval: Default::default(),
+            generation: Default::default(),
+        };
+
+        let settings = config.resolve(&base, Some(GuildId(1)), ChannelId(11));
+        assert_eq!(settings.collection, "guild");
+        assert!(settings
+            .system_message()
+            .starts_with("You answer questions about our product.\n"));
+        assert!(settings.system_message().contains("Cite the passages"));
+        assert_eq!(settings.model, "local");
+        assert_eq!(settings.score_threshold, 0.5);
+        assert_eq!(settings.generation.max_tokens, Some(300));
+        assert_eq!(settings.generation.stop, vec!["Question:"]);
+        let model = config.model_spec(&settings.model);
+        assert_eq!((model.context_window, model.tokens_per_name), (2048, -1));
+        assert_eq!(config.model_spec("gpt-4").context_window, 8192);
+        assert_eq!(settings.trigger, TriggerMode::All);
+        assert!(settings.allows(Some(GuildId(1)), ChannelId(11)));
+        assert!(!settings.allows(Some(GuildId(1)), ChannelId(12)));
+
+        let settings = config.resolve(&base, Some(GuildId(2)), ChannelId(20));
+        assert_eq!(settings.collection, "base");
+        assert_eq!(settings.model, "gpt-3.5-turbo");
+
+        assert!("[guilds.x]\ncollection = \"a\""
+            .parse::<BotConfig>()
+            .is_err());
+        assert!("[default]\nscore_threshold = 2.0"
+            .parse::<BotConfig>()
+            .is_err());
+        assert!("[default]\ncolection = \"a\"".parse::<BotConfig>().is_err());
+        assert!("[default]\nknowledge_top_k = 0"
+            .parse::<BotConfig>()
+            .is_err());
+        assert!("[default]\ntop_p = 1.5".parse::<BotConfig>().is_err());
+        assert!("[models.local]\ncontext_window = 0"
+            .parse::<BotConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let old: BotConfig = r#"
+            [guilds.1]
+            collection = "a"
+
+            [guilds.2]
+            collection = "b"
+        "#
+        .parse()
+        .unwrap();
+        let new: BotConfig = r#"
+            [default]
+            knowledge_top_k = 3
+
+            [guilds.1]
+            collection = "c"
+
+            [channels.11]
+            trigger = "slash"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            new.diff(&old),
+            vec![
+                "default.knowledge_top_k: None -> Some(3)",
+                "guilds.1.collection: Some(\"a\") -> Some(\"c\")",
+                "guilds.2 removed",
+                "channels.11 added",
+            ]
+        );
+        assert!(new.diff(&new).is_empty());
+    }
+}
@@ -1,7 +1,10 @@
 use std::{
+    collections::VecDeque,
+    fmt,
     num::NonZeroUsize,
     ops::{Deref, DerefMut},
-    sync::{Mutex, MutexGuard, PoisonError}, collections::VecDeque,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 
 use async_openai::{
@@ -9,16 +12,139 @@ use async_openai::{
     types::{ChatCompletionRequestMessage, ChatCompletionRequestMessageArgs, Role},
 };
 use lru::LruCache;
-use serenity::model::prelude::UserId;
+use serenity::model::prelude::{ChannelId, GuildId, UserId};
 use thiserror::Error;
 
+/// Messages kept of a conversation, older ones are dropped or summarized.
+pub const MAX_CONVERSATION_LENGTH: usize = 20;
+
 #[derive(Debug, Clone)]
 pub struct ConversationMessage {
     pub role: Role,
     pub message: String,
 }
 
-type UserMessagesMap = LruCache<UserId, ConversationCtx>;
+/// Who posted a message of a conversation, and where in discord.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageOrigin {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+}
+
+/// How messages are grouped into conversations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationScope {
+    /// One conversation per user, wherever they talk to the bot.
+    User,
+    /// One conversation per user in each channel.
+    UserChannel,
+    /// One conversation shared by everyone in a channel.
+    Channel,
+    /// One conversation shared by everyone in a thread, per user and channel elsewhere.
+    Thread,
+}
+
+impl FromStr for ConversationScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Self::User),
+            "user-channel" => Ok(Self::UserChannel),
+            "channel" => Ok(Self::Channel),
+            "thread" => Ok(Self::Thread),
+            _ => Err(format!("Unknown conversation scope: {}", s)),
+        }
+    }
+}
+
+impl ConversationScope {
+    pub fn key(&self, user_id: UserId, channel_id: ChannelId, in_thread: bool) -> ConversationKey {
+        match self {
+            Self::User => ConversationKey::User(user_id),
+            Self::UserChannel => ConversationKey::UserChannel(user_id, channel_id),
+            Self::Channel => ConversationKey::Channel(channel_id),
+            Self::Thread if in_thread => ConversationKey::Thread(channel_id),
+            Self::Thread => ConversationKey::UserChannel(user_id, channel_id),
+        }
+    }
+}
+
+/// Identifies one conversation of the bot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConversationKey {
+    User(UserId),
+    UserChannel(UserId, ChannelId),
+    Channel(ChannelId),
+    Thread(ChannelId),
+}
+
+impl ConversationKey {
+    /// Whether several users take part in the conversation.
+    pub fn is_shared(&self) -> bool {
+        matches!(self, Self::Channel(_) | Self::Thread(_))
+    }
+}
+
+impl fmt::Display for ConversationKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user_id) => write!(f, "user:{}", user_id.0),
+            Self::UserChannel(user_id, channel_id) => {
+                write!(f, "user:{}/channel:{}", user_id.0, channel_id.0)
+            }
+            Self::Channel(channel_id) => write!(f, "channel:{}", channel_id.0),
+            Self::Thread(channel_id) => write!(f, "thread:{}", channel_id.0),
+        }
+    }
+}
+
+/// Turn a discord user name into a name accepted by the chat api, which allows at most 64
+/// letters, digits, underscores and hyphens.
+pub fn participant_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .take(64)
+        .collect();
+    match name.is_empty() {
+        true => "user".into(),
+        false => name,
+    }
+}
+
+/// Keeps the recent history of the conversations with the bot.
+pub trait ConversationStore: Send + Sync {
+    fn add_message(
+        &self,
+        key: ConversationKey,
+        role: Role,
+        message: &str,
+        name: Option<String>,
+        origin: MessageOrigin,
+    ) -> Result<(), ConversationCacheError>;
+
+    fn get_messages(&self, key: ConversationKey)
+        -> Result<ConversationCtx, ConversationCacheError>;
+
+    /// Forget the history of a conversation.
+    fn clear_messages(&self, key: ConversationKey) -> Result<(), ConversationCacheError>;
+
+    /// Replace the oldest `count` messages of a conversation with `summary`, which condenses
+    /// them and the summary before.
+    fn summarize(
+        &self,
+        key: ConversationKey,
+        summary: &str,
+        count: usize,
+    ) -> Result<(), ConversationCacheError>;
+}
+
+type UserMessagesMap = LruCache<ConversationKey, ConversationCtx>;
 #[derive(Debug)]
 pub struct ConversationCache {
     pub map: Mutex<UserMessagesMap>,
@@ -33,7 +159,7 @@ impl Default for ConversationCache {
             NonZeroUsize::new(max_keys_length).expect("Unreachable!"),
         ));
         Self {
-            max_conversation_length: 20,
+            max_conversation_length: MAX_CONVERSATION_LENGTH,
             map,
             max_keys_length,
         }
@@ -46,10 +172,12 @@ pub enum ConversationCacheError {
     ChannelNotFound,
     #[error("Failed to acquire lock on mutex, this should never happen.")]
     MutexPanic,
+    #[error("Conversation database error: {0}")]
+    Database(#[from] rusqlite::Error),
 }
 
-impl From<PoisonError<MutexGuard<'_, UserMessagesMap>>> for ConversationCacheError {
-    fn from(_: PoisonError<MutexGuard<'_, UserMessagesMap>>) -> Self {
+impl<T> From<PoisonError<MutexGuard<'_, T>>> for ConversationCacheError {
+    fn from(_: PoisonError<MutexGuard<'_, T>>) -> Self {
         Self::MutexPanic
     }
 }
@@ -57,13 +185,13 @@ impl From<PoisonError<MutexGuard<'_, UserMessagesMap>>> for ConversationCacheErr
 impl ConversationCache {
     pub fn add_message(
         &self,
-        user_id: UserId,
+        key: ConversationKey,
         role: Role,
         message: &str,
         name: Option<String>,
     ) -> Result<(), ConversationCacheError> {
         let mut map = self.map.lock()?;
-        let ctx = map.get_or_insert_mut(user_id, ConversationCtx::default);
+        let ctx = map.get_or_insert_mut(key, ConversationCtx::default);
 
         ctx.add_message(role, message, name);
         if ctx.value.len() > self.max_conversation_length {
@@ -72,9 +200,81 @@ impl ConversationCache {
         Ok(())
     }
 
-    pub fn get_messages(&self, user_id: UserId) -> Result<ConversationCtx, ConversationCacheError> {
+    pub fn get_messages(
+        &self,
+        key: ConversationKey,
+    ) -> Result<ConversationCtx, ConversationCacheError> {
+        let mut map = self.map.lock()?;
+        Ok(map.get(&key).cloned().unwrap_or_default())
+    }
+
+    /// Replace the cached conversation of `key`, keeping its last messages only.
+    pub fn put_messages(
+        &self,
+        key: ConversationKey,
+        mut ctx: ConversationCtx,
+    ) -> Result<(), ConversationCacheError> {
+        let overflow = ctx.value.len().saturating_sub(self.max_conversation_length);
+        ctx.value.drain(..overflow);
+        self.map.lock()?.put(key, ctx);
+        Ok(())
+    }
+
+    pub fn clear_messages(&self, key: ConversationKey) -> Result<(), ConversationCacheError> {
+        self.map.lock()?.pop(&key);
+        Ok(())
+    }
+
+    pub fn summarize(
+        &self,
+        key: ConversationKey,
+        summary: &str,
+        count: usize,
+    ) -> Result<(), ConversationCacheError> {
         let mut map = self.map.lock()?;
-        Ok(map.get(&user_id).cloned().unwrap_or_default())
+        let ctx = map.get_or_insert_mut(key, ConversationCtx::default);
+        let count = count.min(ctx.value.len());
+        ctx.value.drain(..count);
+        ctx.summary = Some(summary.into());
+        Ok(())
+    }
+
+    /// Whether the conversation of `key` is cached.
+    pub fn contains(&self, key: ConversationKey) -> Result<bool, ConversationCacheError> {
+        Ok(self.map.lock()?.contains(&key))
+    }
+}
+
+impl ConversationStore for ConversationCache {
+    fn add_message(
+        &self,
+        key: ConversationKey,
+        role: Role,
+        message: &str,
+        name: Option<String>,
+        _origin: MessageOrigin,
+    ) -> Result<(), ConversationCacheError> {
+        ConversationCache::add_message(self, key, role, message, name)
+    }
+
+    fn get_messages(
+        &self,
+        key: ConversationKey,
+    ) -> Result<ConversationCtx, ConversationCacheError> {
+        ConversationCache::get_messages(self, key)
+    }
+
+    fn clear_messages(&self, key: ConversationKey) -> Result<(), ConversationCacheError> {
+        ConversationCache::clear_messages(self, key)
+    }
+
+    fn summarize(
+        &self,
+        key: ConversationKey,
+        summary: &str,
+        count: usize,
+    ) -> Result<(), ConversationCacheError> {
+        ConversationCache::summarize(self, key, summary, count)
     }
 }
 
@@ -92,6 +292,8 @@ impl TryFrom<ConversationMessage> for ChatCompletionRequestMessage {
 #[derive(Debug, Clone, Default)]
 pub struct ConversationCtx {
     pub value: VecDeque<ChatCompletionRequestMessage>,
+    /// The earlier messages of the conversation, condensed by the model.
+    pub summary: Option<String>,
 }
 
 impl From<ConversationCtx> for VecDeque<ChatCompletionRequestMessage> {
@@ -0,0 +1,105 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::retry::RequestError;
+
+/// Reply to a question which doesn't fit into the context of the model.
+pub const QUESTION_TOO_LONG_REPLY: &str = "I apologize, but could you please provide a shorter question? It would be easier for me to assist you if the question is more concise. Thank you!";
+
+/// Short id of the handling of a message or command, shown to the user when it fails and
+/// attached to every log line of it, so the logs of a failure can be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationId(String);
+
+impl CorrelationId {
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4().simple().to_string()[..8].to_string())
+    }
+}
+
+impl Default for CorrelationId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a question couldn't be answered.
+#[derive(Error, Debug)]
+pub enum AnswerError {
+    /// The question and the history kept for it exceed the context of the model.
+    #[error("Question is too long")]
+    QuestionTooLong,
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error("Loading the conversation failed: {0:#}")]
+    Conversation(anyhow::Error),
+    #[error("Discord request failed: {0}")]
+    Discord(#[from] serenity::Error),
+    #[error("{0:#}")]
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for AnswerError {
+    fn from(why: anyhow::Error) -> Self {
+        let why = match why.downcast::<RequestError>() {
+            Ok(error) => return AnswerError::Request(error),
+            Err(why) => why,
+        };
+        match why.downcast::<serenity::Error>() {
+            Ok(error) => AnswerError::Discord(error),
+            Err(why) => AnswerError::Internal(why),
+        }
+    }
+}
+
+impl AnswerError {
+    /// What the user who asked is told. Failures which aren't caused by the question carry `id`
+    /// to look them up in the logs.
+    pub fn reply(&self, id: &CorrelationId) -> String {
+        let reply = match self {
+            AnswerError::QuestionTooLong => return QUESTION_TOO_LONG_REPLY.into(),
+            AnswerError::Request(error) => error.reply(),
+            AnswerError::Conversation(_) => {
+                "I couldn't load our conversation right now. Please try again in a moment."
+            }
+            AnswerError::Discord(_) => {
+                "I couldn't post my answer here. Please check that I'm allowed to send messages in this channel."
+            }
+            AnswerError::Internal(_) => "Something went wrong while I was answering.",
+        };
+        format!("{} (error id: `{}`)", reply, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::{AnswerError, CorrelationId, QUESTION_TOO_LONG_REPLY};
+    use crate::retry::RequestError;
+
+    #[test]
+    fn test_answer_error_reply() {
+        let id = CorrelationId::new();
+        assert_eq!(id.to_string().len(), 8);
+
+        let error: AnswerError =
+            anyhow::Error::from(RequestError::Unavailable("502".into())).into();
+        assert!(matches!(error, AnswerError::Request(_)));
+        assert!(error.reply(&id).ends_with(&format!("(error id: `{}`)", id)));
+
+        let error: AnswerError = anyhow!("Unexpected").into();
+        assert!(matches!(error, AnswerError::Internal(_)));
+        assert_eq!(
+            AnswerError::QuestionTooLong.reply(&id),
+            QUESTION_TOO_LONG_REPLY
+        );
+    }
+}
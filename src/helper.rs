@@ -11,13 +11,13 @@ macro_rules! try_log {
 }
 pub(crate) use try_log;
 
-// Extract value from qdrant::Value, return Err if value is not present or has different Kind
+// Extract value from a payload, return Err if value is not present or has different Kind
 macro_rules! try_match {
     ($expr:expr, $key:expr, $kind:tt) => {
         match $expr.get($key) {
             Some(value) => {
-                if let Some(Kind::$kind(value)) = value.kind.clone() {
-                    value
+                if let serde_json::Value::$kind(value) = value {
+                    value.clone()
                 } else {
                     return Err(anyhow!("'{}' has different Kind", $key))
                 }
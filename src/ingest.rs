@@ -0,0 +1,249 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, warn};
+
+use crate::knowledge_base::KnowledgePayload;
+
+/// Documents found under a path, and the files which couldn't be turned into documents.
+#[derive(Debug, Default)]
+pub struct LoadedDocuments {
+    pub documents: Vec<KnowledgePayload>,
+    /// Files and entries which were left out on purpose, with the reason.
+    pub skipped: Vec<(String, String)>,
+    /// Files and entries which couldn't be parsed, with the error.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Load the knowledge documents of a file, or of every supported file below a directory.
+/// `.json` files hold one document or an array of them, `.jsonl` files one document per line,
+/// and `.md` and `.txt` files are a document each, with an optional front matter giving the
+/// `id`, `title` and `url` of Markdown documents. Files without an id or url are identified by
+/// their path below `root`, so they keep their id whether they are loaded alone or with their
+/// directory.
+pub fn load_documents(path: &Path, root: &Path) -> Result<LoadedDocuments> {
+    let mut loaded = LoadedDocuments::default();
+    let mut files = Vec::new();
+    if path.is_dir() {
+        collect_files(path, &mut files)?;
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    } else {
+        return Err(anyhow!("{:?} is neither a file nor a directory", path));
+    }
+    let root = root.canonicalize()?;
+
+    for file in files {
+        let source = file.display().to_string();
+        let extension = file
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+        let text = match extension.as_deref() {
+            Some("json" | "jsonl" | "md" | "markdown" | "txt") => match fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(why) => {
+                    loaded.failed.push((source, why.to_string()));
+                    continue;
+                }
+            },
+            _ => {
+                loaded
+                    .skipped
+                    .push((source, "unsupported file type".into()));
+                continue;
+            }
+        };
+
+        debug!("Parsing {}", source);
+        match extension.as_deref() {
+            Some("json") => match parse_json(&text) {
+                Ok(documents) => push_documents(&mut loaded, &source, documents),
+                Err(why) => loaded.failed.push((source, why.to_string())),
+            },
+            Some("jsonl") => {
+                for (i, line) in text.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let source = format!("{}:{}", source, i + 1);
+                    match serde_json::from_str::<KnowledgePayload>(line) {
+                        Ok(document) => push_documents(&mut loaded, &source, vec![document]),
+                        Err(why) => loaded.failed.push((source, why.to_string())),
+                    }
+                }
+            }
+            Some("txt") => {
+                let document = KnowledgePayload {
+                    id: Some(file_id(&file, &root)),
+                    url: String::new(),
+                    title: file_title(&file),
+                    content: text.trim().to_string(),
+                };
+                push_documents(&mut loaded, &source, vec![document])
+            }
+            _ => {
+                let mut document = parse_markdown(&text, file_title(&file));
+                if document.id.is_none() && document.url.is_empty() {
+                    document.id = Some(file_id(&file, &root));
+                }
+                push_documents(&mut loaded, &source, vec![document])
+            }
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Every file below `dir`, in a stable order, leaving out hidden files and directories.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|x| x.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let hidden = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.starts_with('.'))
+            .unwrap_or(false);
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn push_documents(loaded: &mut LoadedDocuments, source: &str, documents: Vec<KnowledgePayload>) {
+    for document in documents {
+        if document.content.trim().is_empty() {
+            warn!("{} has no content", source);
+            loaded.skipped.push((source.into(), "empty content".into()));
+        } else {
+            loaded.documents.push(document);
+        }
+    }
+}
+
+fn file_title(file: &Path) -> String {
+    file.file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The path of `file` below the canonical `root`, or its whole canonical path if it isn't below.
+fn file_id(file: &Path, root: &Path) -> String {
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    file.strip_prefix(root)
+        .unwrap_or(&file)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+fn parse_json(text: &str) -> Result<Vec<KnowledgePayload>> {
+    match text.trim_start().starts_with('[') {
+        true => Ok(serde_json::from_str(text)?),
+        false => Ok(vec![serde_json::from_str(text)?]),
+    }
+}
+
+/// Build a document from Markdown. `id`, `title` and `url` come from the front matter, the title
+/// falls back to the first heading and then to `default_title`.
+pub fn parse_markdown(text: &str, default_title: String) -> KnowledgePayload {
+    let mut id = None;
+    let mut title = None;
+    let mut url = String::new();
+    let mut body = text.trim_start();
+
+    if let Some(rest) = body.strip_prefix("---") {
+        if let Some(end) = rest.find("\n---") {
+            for line in rest[..end].lines() {
+                if let Some((key, value)) = line.split_once(':') {
+                    let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+                    match key.trim() {
+                        "id" => id = Some(value.to_string()),
+                        "title" => title = Some(value.to_string()),
+                        "url" => url = value.to_string(),
+                        _ => {}
+                    }
+                }
+            }
+            let rest = &rest[end + "\n---".len()..];
+            body = rest.split_once('\n').map(|x| x.1).unwrap_or_default();
+        }
+    }
+
+    let title = title
+        .or_else(|| {
+            body.lines()
+                .find_map(|line| line.strip_prefix("# "))
+                .map(|x| x.trim().to_string())
+        })
+        .unwrap_or(default_title);
+    KnowledgePayload {
+        id,
+        url,
+        title,
+        content: body.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_documents, parse_markdown};
+
+    #[test]
+    fn test_parse_markdown() {
+        let text = "---\ntitle: \"Getting started\"\nurl: https://example.com/start\n---\n# Start\n\nRun it.\n";
+        let document = parse_markdown(text, "start".into());
+        assert_eq!(document.title, "Getting started");
+        assert_eq!(document.url, "https://example.com/start");
+        assert_eq!(document.content, "# Start\n\nRun it.");
+
+        let document = parse_markdown("Intro\n# Install\nRun it.", "install".into());
+        assert_eq!(document.title, "Install");
+        assert_eq!(document.url, "");
+
+        let document = parse_markdown("Just text", "notes".into());
+        assert_eq!(document.title, "notes");
+        assert_eq!(document.content, "Just text");
+    }
+
+    #[test]
+    fn test_load_documents_from_directory() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(
+            dir.join("docs.jsonl"),
+            "{\"url\":\"a\",\"title\":\"A\",\"content\":\"first\"}\n\nnot json\n{\"url\":\"b\",\"title\":\"B\",\"content\":\"\"}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("nested/guide.md"), "# Guide\nRead me.").unwrap();
+        std::fs::write(dir.join("nested/notes.txt"), "Some notes").unwrap();
+        std::fs::write(dir.join("image.png"), "").unwrap();
+
+        let loaded = load_documents(&dir, &dir).unwrap();
+        let titles: Vec<&str> = loaded.documents.iter().map(|x| x.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "Guide", "notes"]);
+        assert_eq!(loaded.documents[0].document_id(), "a");
+        assert_eq!(loaded.documents[1].document_id(), "nested/guide.md");
+        assert_eq!(loaded.skipped.len(), 2);
+        assert_eq!(loaded.failed.len(), 1);
+        assert!(loaded.failed[0].0.ends_with("docs.jsonl:3"));
+
+        // A file loaded alone keeps the id it has when loaded with its directory.
+        let loaded = load_documents(&dir.join("nested/./guide.md"), &dir).unwrap();
+        assert_eq!(loaded.documents[0].document_id(), "nested/guide.md");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
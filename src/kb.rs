@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use tracing::info;
+
+use crate::{
+    ai::TokenEncoder,
+    knowledge_base::{group_chunks, KnowledgeChunk, KnowledgeClient},
+    vector_store::{Condition, Filter},
+};
+
+/// A document of a knowledge base, as found in its chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentInfo {
+    pub doc_id: String,
+    pub title: String,
+    pub url: String,
+    pub chunks: usize,
+}
+
+/// The documents `chunks` belong to, ordered by title.
+pub fn list_documents(chunks: &[KnowledgeChunk]) -> Vec<DocumentInfo> {
+    let mut documents: HashMap<&str, DocumentInfo> = HashMap::new();
+    for chunk in chunks {
+        documents
+            .entry(&chunk.doc_id)
+            .or_insert_with(|| DocumentInfo {
+                doc_id: chunk.doc_id.clone(),
+                title: chunk.title.clone(),
+                url: chunk.url.clone(),
+                chunks: 0,
+            })
+            .chunks += 1;
+    }
+    let mut documents: Vec<DocumentInfo> = documents.into_values().collect();
+    documents.sort_by(|a, b| (&a.title, &a.doc_id).cmp(&(&b.title, &b.doc_id)));
+    documents
+}
+
+/// Page `page`, counted from 1, of `documents`, one line per document.
+pub fn format_documents(documents: &[DocumentInfo], page: usize, page_size: usize) -> String {
+    if documents.is_empty() {
+        return "No documents".into();
+    }
+    let pages = documents.len().div_ceil(page_size);
+    let start = (page.max(1) - 1) * page_size;
+    let shown = documents.iter().skip(start).take(page_size);
+    let mut lines = vec![format!(
+        "Page {} of {}, {} documents",
+        page.max(1),
+        pages,
+        documents.len()
+    )];
+    lines.extend(shown.map(|x| {
+        let title = match x.url.is_empty() {
+            true => x.title.clone(),
+            false => format!("{} ({})", x.title, x.url),
+        };
+        format!("{:>4} chunks  {}  [{}]", x.chunks, title, x.doc_id)
+    }));
+    lines.join("\n")
+}
+
+/// What a collection holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnowledgeStats {
+    pub documents: usize,
+    pub chunks: usize,
+    pub max_chunks: usize,
+    /// Tokens of all chunks, counting the overlap of consecutive chunks twice.
+    pub tokens: usize,
+}
+
+impl KnowledgeStats {
+    pub fn new(chunks: &[KnowledgeChunk], encoder: &TokenEncoder) -> Self {
+        let documents = list_documents(chunks);
+        Self {
+            documents: documents.len(),
+            chunks: chunks.len(),
+            max_chunks: documents.iter().map(|x| x.chunks).max().unwrap_or_default(),
+            tokens: chunks
+                .iter()
+                .map(|x| encoder.0.encode_with_special_tokens(&x.content).len())
+                .sum(),
+        }
+    }
+}
+
+impl fmt::Display for KnowledgeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let average = match self.documents {
+            0 => 0.0,
+            documents => self.chunks as f64 / documents as f64,
+        };
+        writeln!(f, "Documents: {}", self.documents)?;
+        writeln!(
+            f,
+            "Chunks: {} ({:.1} per document, at most {})",
+            self.chunks, average, self.max_chunks
+        )?;
+        write!(f, "Tokens: {}", self.tokens)
+    }
+}
+
+/// A `key=value` condition on the payload of the chunks. Values which are numbers match
+/// integer fields, others match keywords.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadCondition {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for PayloadCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Self {
+                key: key.into(),
+                value: value.into(),
+            }),
+            _ => Err(format!("Expect key=value, got {:?}", s)),
+        }
+    }
+}
+
+impl From<&PayloadCondition> for Condition {
+    fn from(condition: &PayloadCondition) -> Self {
+        match condition.value.parse::<i64>() {
+            Ok(value) => Condition::Integer {
+                key: condition.key.clone(),
+                value,
+            },
+            Err(_) => Condition::keyword(&condition.key, &condition.value),
+        }
+    }
+}
+
+/// The chunks of the documents with any of `ids` or `urls`, narrowed down by `conditions`.
+/// Fails without any of them, so nothing is selected by accident.
+pub fn document_filter(
+    ids: &[String],
+    urls: &[String],
+    conditions: &[PayloadCondition],
+) -> Result<Filter> {
+    if ids.is_empty() && urls.is_empty() && conditions.is_empty() {
+        return Err(anyhow!("Give documents by --id, --url or --filter"));
+    }
+    Ok(Filter {
+        should: ids
+            .iter()
+            .map(|x| Condition::keyword("doc_id", x))
+            .chain(urls.iter().map(|x| Condition::keyword("url", x)))
+            .collect(),
+        must: conditions.iter().map(Condition::from).collect(),
+        ..Default::default()
+    })
+}
+
+pub async fn list(
+    knowledge_client: &KnowledgeClient,
+    collection: &str,
+    page: usize,
+    page_size: usize,
+) -> Result<()> {
+    let chunks = knowledge_client.scroll_chunks(collection, None).await?;
+    println!(
+        "{}",
+        format_documents(&list_documents(&chunks), page, page_size)
+    );
+    Ok(())
+}
+
+/// Print the document with the id or url `document`.
+pub async fn show(
+    knowledge_client: &KnowledgeClient,
+    collection: &str,
+    document: &str,
+) -> Result<()> {
+    let filter = document_filter(&[document.into()], &[document.into()], &[])?;
+    let chunks = knowledge_client
+        .scroll_chunks(collection, Some(filter))
+        .await?;
+    if chunks.is_empty() {
+        return Err(anyhow!("No document {:?} in {}", document, collection));
+    }
+    for info in list_documents(&chunks) {
+        let chunks: Vec<KnowledgeChunk> = chunks
+            .iter()
+            .filter(|x| x.doc_id == info.doc_id)
+            .cloned()
+            .collect();
+        for document in group_chunks(chunks) {
+            println!("Id: {}", info.doc_id);
+            println!("Title: {}", info.title);
+            println!("Url: {}", info.url);
+            println!("Chunks: {}\n", info.chunks);
+            println!("{}\n", document.content);
+        }
+    }
+    Ok(())
+}
+
+/// Delete the chunks of the documents selected like `document_filter`, or only count them
+/// with `dry_run`.
+pub async fn delete(
+    knowledge_client: &KnowledgeClient,
+    collection: &str,
+    filter: Filter,
+    dry_run: bool,
+) -> Result<()> {
+    let count = knowledge_client.count(collection, Some(&filter)).await?;
+    if dry_run || count == 0 {
+        println!("Would delete {} chunks", count);
+        return Ok(());
+    }
+    knowledge_client.delete(collection, &filter).await?;
+    println!("Deleted {} chunks", count);
+    Ok(())
+}
+
+pub async fn stats(knowledge_client: &KnowledgeClient, collection: &str) -> Result<()> {
+    let info = knowledge_client
+        .collection_info(collection)
+        .await?
+        .ok_or_else(|| anyhow!("No collection {}", collection))?;
+    let chunks = knowledge_client.scroll_chunks(collection, None).await?;
+    println!("Collection: {} ({})", collection, info.status);
+    println!(
+        "Points: {}, vector size: {}, distance: {:?}",
+        info.points, info.config.vector_size, info.config.distance
+    );
+    println!("{}", KnowledgeStats::new(&chunks, &TokenEncoder::new()?));
+    Ok(())
+}
+
+/// Write the documents of `collection` as JSON lines, which `update` reads back, into `output`
+/// or to stdout.
+pub async fn export(
+    knowledge_client: &KnowledgeClient,
+    collection: &str,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut chunks = knowledge_client.scroll_chunks(collection, None).await?;
+    chunks.sort_by(|a, b| (&a.doc_id, a.chunk_index).cmp(&(&b.doc_id, b.chunk_index)));
+    let documents = group_chunks(chunks);
+
+    let mut writer: Box<dyn Write> = match &output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    for document in documents.iter() {
+        writeln!(writer, "{}", serde_json::to_string(document)?)?;
+    }
+    writer.flush()?;
+    if let Some(path) = output {
+        info!("Exported {} documents to {:?}", documents.len(), path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        document_filter, format_documents, list_documents, KnowledgeStats, PayloadCondition,
+    };
+    use crate::{ai::TokenEncoder, knowledge_base::KnowledgeChunk};
+
+    fn chunk(doc_id: &str, chunk_index: i64, title: &str) -> KnowledgeChunk {
+        KnowledgeChunk {
+            doc_id: doc_id.into(),
+            chunk_index,
+            content_hash: String::new(),
+            title: title.into(),
+            url: format!("https://{}", doc_id),
+            content: "Some content".into(),
+        }
+    }
+
+    #[test]
+    fn test_list_documents() {
+        let chunks = vec![
+            chunk("b", 0, "Beta"),
+            chunk("a", 1, "Alpha"),
+            chunk("b", 1, "Beta"),
+            chunk("a", 0, "Alpha"),
+            chunk("a", 2, "Alpha"),
+        ];
+        let documents = list_documents(&chunks);
+        assert_eq!(documents.len(), 2);
+        assert_eq!(
+            (documents[0].title.as_str(), documents[0].chunks),
+            ("Alpha", 3)
+        );
+
+        let page = format_documents(&documents, 2, 1);
+        let lines: Vec<&str> = page.lines().collect();
+        assert_eq!(lines[0], "Page 2 of 2, 2 documents");
+        assert_eq!(lines[1], "   2 chunks  Beta (https://b)  [b]");
+
+        let stats = KnowledgeStats::new(&chunks, &TokenEncoder::new().unwrap());
+        assert_eq!((stats.documents, stats.chunks, stats.max_chunks), (2, 5, 3));
+        assert!(stats.to_string().contains("2.5 per document"));
+    }
+
+    #[test]
+    fn test_document_filter() {
+        assert!(document_filter(&[], &[], &[]).is_err());
+        assert!("title".parse::<PayloadCondition>().is_err());
+
+        let condition: PayloadCondition = "chunk_index=0".parse().unwrap();
+        let filter = document_filter(&["a".into()], &[], &[condition]).unwrap();
+        assert_eq!(filter.should.len(), 1);
+        assert_eq!(filter.must.len(), 1);
+    }
+}
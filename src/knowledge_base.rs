@@ -1,37 +1,107 @@
 use anyhow::{anyhow, Result};
-use async_openai::{types::CreateEmbeddingRequestArgs, Client as OpenAIClient};
-use std::{collections::HashMap, ops::Deref, path::PathBuf};
-use tracing::{error, info, trace};
+use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, trace, warn};
 use uuid::Uuid;
 
-use qdrant_client::{
-    prelude::{Payload, QdrantClient, QdrantClientConfig},
-    qdrant::{
-        value::Kind, vectors_config::Config, with_payload_selector::SelectorOptions,
-        CollectionOperationResponse, CountPoints, CreateCollection, Distance, PointStruct,
-        PointsOperationResponse, SearchPoints, Value, VectorParams, VectorsConfig,
-        WithPayloadSelector,
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ai::{EmbeddingProvider, TokenEncoder, TokenUsage},
+    chunker::{chunk_text, merge_chunks, ChunkConfig},
+    helper::try_match,
+    ingest::LoadedDocuments,
+    qdrant_store::QdrantStore,
+    retrieval::RetrievalConfig,
+    usage::{RequestKind, UsageRecord, UsageStore},
+    vector_store::{
+        CollectionConfig, Condition, Distance, Filter, IndexType, Payload, ScrollPage, VectorPoint,
+        VectorStore,
     },
 };
-use serde::{Deserialize, Serialize};
 
-use crate::helper::try_match;
+/// Number of documents chunked together, and of chunks embedded with one request.
+const INGEST_BATCH_SIZE: usize = 32;
+/// Number of points upserted with one request.
+pub(crate) const UPSERT_BATCH_SIZE: usize = 128;
+/// Number of chunks searched for each document a query should return, before chunks are grouped
+/// by document.
+const QUERY_CHUNKS_PER_DOCUMENT: usize = 4;
+/// Number of points fetched with one scroll request.
+const SCROLL_PAGE_SIZE: usize = 256;
+/// Dimensions of the embeddings of the knowledge bases.
+const EMBEDDING_SIZE: u64 = 1536;
+/// Interval between two checks whether the vector store still answers.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Maximal interval between two attempts to reconnect to the vector store.
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KnowledgePayload {
+    /// Identifies the document across updates. Falls back to the url, and then to the title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub url: String,
     pub title: String,
     pub content: String,
 }
 
-impl TryFrom<HashMap<String, Value>> for KnowledgePayload {
+impl KnowledgePayload {
+    pub fn document_id(&self) -> String {
+        match &self.id {
+            Some(id) if !id.is_empty() => id.clone(),
+            _ if !self.url.is_empty() => self.url.clone(),
+            _ => self.title.clone(),
+        }
+    }
+}
+
+/// Hash of everything the stored chunks of a document are derived from, so a document whose hash
+/// didn't change doesn't need to be embedded again.
+pub fn content_hash(document: &KnowledgePayload, config: ChunkConfig) -> String {
+    let mut hasher = Sha256::new();
+    for field in [&document.title, &document.url, &document.content] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(config.max_tokens.to_le_bytes());
+    hasher.update(config.overlap_tokens.to_le_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Point ID of a chunk, the same every time the chunk is upserted.
+pub fn point_id(doc_id: &str, chunk_index: i64) -> String {
+    let name = format!("{}#{}", doc_id, chunk_index);
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()).to_string()
+}
+
+fn payload_string(payload: &Payload, key: &str) -> Option<String> {
+    payload.get(key).and_then(|x| x.as_str()).map(String::from)
+}
+
+impl TryFrom<Payload> for KnowledgePayload {
     type Error = anyhow::Error;
 
-    fn try_from(value: HashMap<String, Value>) -> Result<Self, Self::Error> {
-        let url = try_match!(value, "url", StringValue);
-        let title = try_match!(value, "title", StringValue);
-        let content = try_match!(value, "content", StringValue);
+    fn try_from(value: Payload) -> Result<Self, Self::Error> {
+        let url = try_match!(value, "url", String);
+        let title = try_match!(value, "title", String);
+        let content = try_match!(value, "content", String);
         Ok(Self {
+            id: None,
             url,
             title,
             content,
@@ -39,169 +109,849 @@ impl TryFrom<HashMap<String, Value>> for KnowledgePayload {
     }
 }
 
-pub struct KnowledgeClient {
-    pub client: QdrantClient,
+/// A part of a document, stored as one point of a collection.
+#[derive(Debug, Clone)]
+pub struct KnowledgeChunk {
+    pub doc_id: String,
+    pub chunk_index: i64,
+    pub content_hash: String,
+    pub title: String,
+    pub url: String,
+    pub content: String,
 }
 
-impl KnowledgeClient {
-    pub async fn new(url: &str) -> Result<Self> {
-        let config = QdrantClientConfig::from_url(url);
+impl TryFrom<Payload> for KnowledgeChunk {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Payload) -> Result<Self, Self::Error> {
+        let url = try_match!(value, "url", String);
+        let title = try_match!(value, "title", String);
+        let content = try_match!(value, "content", String);
+
+        // Points upserted before documents were chunked hold a whole document each.
+        let doc_id =
+            payload_string(&value, "doc_id").unwrap_or_else(|| format!("{}#{}", url, title));
+        let chunk_index = value
+            .get("chunk_index")
+            .and_then(|x| x.as_i64())
+            .unwrap_or_default();
+        let content_hash = payload_string(&value, "content_hash").unwrap_or_default();
         Ok(Self {
-            client: QdrantClient::new(Some(config)).await?,
+            doc_id,
+            chunk_index,
+            content_hash,
+            title,
+            url,
+            content,
         })
     }
 }
 
+impl From<KnowledgeChunk> for Payload {
+    fn from(chunk: KnowledgeChunk) -> Self {
+        let mut payload = Payload::new();
+        payload.insert("doc_id".into(), chunk.doc_id.into());
+        payload.insert("chunk_index".into(), chunk.chunk_index.into());
+        payload.insert("content_hash".into(), chunk.content_hash.into());
+        payload.insert("title".into(), chunk.title.into());
+        payload.insert("content".into(), chunk.content.into());
+        payload.insert("url".into(), chunk.url.into());
+        payload
+    }
+}
+
+/// Split a document into the chunks which get embedded and stored.
+pub fn chunk_document(
+    document: &KnowledgePayload,
+    encoder: &TokenEncoder,
+    config: ChunkConfig,
+) -> Vec<KnowledgeChunk> {
+    let doc_id = document.document_id();
+    let hash = content_hash(document, config);
+    chunk_text(encoder, &document.content, config)
+        .into_iter()
+        .enumerate()
+        .map(|(i, content)| KnowledgeChunk {
+            doc_id: doc_id.clone(),
+            chunk_index: i as i64,
+            content_hash: hash.clone(),
+            title: document.title.clone(),
+            url: document.url.clone(),
+            content,
+        })
+        .collect()
+}
+
+/// Group chunks, ordered by relevance, into their documents. Documents keep the order of their
+/// most relevant chunk, and their chunks are joined in the order of the document.
+pub fn group_chunks(chunks: Vec<KnowledgeChunk>) -> Vec<KnowledgePayload> {
+    let mut documents: Vec<Vec<KnowledgeChunk>> = Vec::new();
+    for chunk in chunks {
+        match documents.iter_mut().find(|x| x[0].doc_id == chunk.doc_id) {
+            Some(document) => document.push(chunk),
+            None => documents.push(vec![chunk]),
+        }
+    }
+
+    documents
+        .into_iter()
+        .map(|mut chunks| {
+            chunks.sort_by_key(|x| x.chunk_index);
+            let mut content = String::new();
+            let mut previous_index = None;
+            for chunk in chunks.iter() {
+                content = match previous_index {
+                    Some(index) if index + 1 == chunk.chunk_index => {
+                        merge_chunks(&content, &chunk.content)
+                    }
+                    Some(_) => format!("{}\n\n…\n\n{}", content, chunk.content),
+                    None => chunk.content.clone(),
+                };
+                previous_index = Some(chunk.chunk_index);
+            }
+            KnowledgePayload {
+                id: Some(chunks[0].doc_id.clone()),
+                url: chunks[0].url.clone(),
+                title: chunks[0].title.clone(),
+                content,
+            }
+        })
+        .collect()
+}
+
+/// Whether the vector store answers, as found by the last health check.
+#[derive(Debug, Default)]
+struct Health {
+    /// Seconds since the unix epoch the vector store doesn't answer since, 0 while it does.
+    down_since: AtomicU64,
+    /// Wakes the health checks up to check right away.
+    check: Notify,
+}
+
+impl Health {
+    /// Mark the vector store as reachable, and tell whether it wasn't before.
+    fn mark_up(&self) -> bool {
+        self.down_since.swap(0, Ordering::SeqCst) != 0
+    }
+
+    /// Mark the vector store as unreachable, and tell whether it was before.
+    fn mark_down(&self, now: SystemTime) -> bool {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .max(1);
+        self.down_since
+            .compare_exchange(0, now, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    fn down_since(&self) -> Option<SystemTime> {
+        match self.down_since.load(Ordering::SeqCst) {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+}
+
+pub struct KnowledgeClient {
+    pub store: Arc<dyn VectorStore>,
+    health: Arc<Health>,
+}
+
+impl KnowledgeClient {
+    pub fn new(store: Arc<dyn VectorStore>) -> Self {
+        Self {
+            store,
+            health: Default::default(),
+        }
+    }
+
+    /// A client of the Qdrant server at `url`, which is connected to on the first request.
+    pub async fn qdrant(url: &str) -> Result<Self> {
+        Ok(Self::new(Arc::new(QdrantStore::new(url).await?)))
+    }
+
+    /// Whether Qdrant answered the last health check. Questions are answered without knowledge
+    /// while it doesn't.
+    pub fn is_available(&self) -> bool {
+        self.health.down_since().is_none()
+    }
+
+    /// Since when the vector store doesn't answer, if it doesn't.
+    pub fn down_since(&self) -> Option<SystemTime> {
+        self.health.down_since()
+    }
+
+    /// Check the vector store periodically, and while it doesn't answer, try to reconnect with
+    /// growing pauses until it answers again.
+    pub fn watch_health(&self) {
+        let store = self.store.clone();
+        let health = self.health.clone();
+        tokio::spawn(async move {
+            let mut backoff = ExponentialBackoffBuilder::new()
+                .with_max_interval(MAX_RECONNECT_INTERVAL)
+                .with_max_elapsed_time(None)
+                .build();
+            loop {
+                match store.health_check().await {
+                    Ok(_) => {
+                        if health.mark_up() {
+                            info!("The vector store is reachable again, answering with knowledge");
+                        }
+                        backoff.reset();
+                        tokio::select! {
+                            _ = tokio::time::sleep(HEALTH_CHECK_INTERVAL) => {}
+                            _ = health.check.notified() => {}
+                        }
+                    }
+                    Err(why) => {
+                        if health.mark_down(SystemTime::now()) {
+                            warn!(
+                                "The vector store is unreachable: {}, answering without knowledge until it's back",
+                                why
+                            );
+                        }
+                        let wait = backoff.next_backoff().unwrap_or(MAX_RECONNECT_INTERVAL);
+                        debug!("Reconnecting to the vector store in {:?}", wait);
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
 impl KnowledgeClient {
     pub async fn query_knowledge(
         &self,
         collection_name: &str,
         embedding: Vec<f32>,
         score_threshold: Option<f32>,
+        limit: usize,
     ) -> Result<Vec<KnowledgePayload>> {
         let points = self
-            .search_points(&SearchPoints {
-                collection_name: collection_name.into(),
-                vector: embedding,
-                limit: 3,
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(SelectorOptions::Enable(true)),
-                }),
+            .search(
+                collection_name,
+                &embedding,
+                None,
+                limit * QUERY_CHUNKS_PER_DOCUMENT,
                 score_threshold,
-                ..Default::default()
-            })
-            .await?;
-        if points.result.is_empty() {
+            )
+            .await
+            // Find out whether the vector store went down, or only this search failed.
+            .inspect_err(|_| self.health.check.notify_one())?;
+        if points.is_empty() {
             return Err(anyhow!("No knowledge found"));
         }
-        trace!("query_knowledge costs: {}", points.time);
-        let result = points
-            .result
+        let chunks = points
             .into_iter()
             .map(|x| x.payload.try_into())
-            .collect::<Result<Vec<KnowledgePayload>>>()?;
+            .collect::<Result<Vec<KnowledgeChunk>>>()?;
+        let mut result = group_chunks(chunks);
+        result.truncate(limit);
         Ok(result)
     }
 
-    pub async fn create_knowledge_collection(
+    /// Create the collection of a knowledge base, unless it exists. Tells whether it was created.
+    pub async fn create_knowledge_collection(&self, collection_name: &str) -> Result<bool> {
+        if self.collection_info(collection_name).await?.is_some() {
+            return Ok(false);
+        }
+        let config = CollectionConfig {
+            vector_size: EMBEDDING_SIZE,
+            distance: Distance::Cosine,
+            indexes: [("doc_id".to_string(), IndexType::Keyword)].into(),
+        };
+        self.create_collection(collection_name, &config).await?;
+        Ok(true)
+    }
+
+    /// The content hashes of the stored chunks of each of `doc_ids`.
+    pub async fn document_hashes(
         &self,
         collection_name: &str,
-    ) -> Result<Option<CollectionOperationResponse>> {
-        if self.has_collection(collection_name).await? {
-            return Ok(None);
-        }
-        let response = self
-            .create_collection(&CreateCollection {
-                collection_name: collection_name.into(),
-                vectors_config: Some(VectorsConfig {
-                    config: Some(Config::Params(VectorParams {
-                        size: 1536,
-                        distance: Distance::Cosine.into(),
-                    })),
-                }),
-                ..Default::default()
-            })
-            .await?;
-        Ok(Some(response))
+        doc_ids: &[String],
+    ) -> Result<HashMap<String, Vec<String>>> {
+        let mut hashes: HashMap<String, Vec<String>> = HashMap::new();
+        let filter = Filter {
+            should: doc_ids
+                .iter()
+                .map(|x| Condition::keyword("doc_id", x))
+                .collect(),
+            ..Default::default()
+        };
+        for point in self
+            .scroll_points(collection_name, Some(filter), false)
+            .await?
+        {
+            if let Some(doc_id) = payload_string(&point.payload, "doc_id") {
+                let hash = payload_string(&point.payload, "content_hash").unwrap_or_default();
+                hashes.entry(doc_id).or_default().push(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// A page of the points of `collection_name` matching `filter` from `offset` on, with their
+    /// vectors if `with_vectors`, and the offset of the next page if there is one.
+    pub async fn scroll_page(
+        &self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+        offset: Option<&str>,
+        with_vectors: bool,
+    ) -> Result<ScrollPage> {
+        self.scroll(
+            collection_name,
+            filter,
+            offset,
+            SCROLL_PAGE_SIZE,
+            with_vectors,
+        )
+        .await
+    }
+
+    /// All points of `collection_name` matching `filter`, fetched page by page, with their
+    /// vectors if `with_vectors`.
+    pub async fn scroll_points(
+        &self,
+        collection_name: &str,
+        filter: Option<Filter>,
+        with_vectors: bool,
+    ) -> Result<Vec<VectorPoint>> {
+        let mut points = Vec::new();
+        let mut offset = None;
+        loop {
+            let (mut page, next) = self
+                .scroll_page(
+                    collection_name,
+                    filter.as_ref(),
+                    offset.as_deref(),
+                    with_vectors,
+                )
+                .await?;
+            points.append(&mut page);
+            match next {
+                Some(next) => offset = Some(next),
+                None => return Ok(points),
+            }
+        }
+    }
+
+    /// All chunks of `collection_name` matching `filter`.
+    pub async fn scroll_chunks(
+        &self,
+        collection_name: &str,
+        filter: Option<Filter>,
+    ) -> Result<Vec<KnowledgeChunk>> {
+        self.scroll_points(collection_name, filter, false)
+            .await?
+            .into_iter()
+            .map(|x| x.payload.try_into())
+            .collect()
+    }
+
+    /// Delete the chunks of each of the `documents`, given by one of their current chunks, which
+    /// don't have its current content hash and are left over from older versions of the
+    /// document. Points stored before documents were chunked have no doc_id, they are matched
+    /// by their url and title.
+    pub async fn delete_stale_chunks(
+        &self,
+        collection_name: &str,
+        documents: &[KnowledgeChunk],
+    ) -> Result<()> {
+        let filter = Filter {
+            should: documents
+                .iter()
+                .flat_map(|chunk| {
+                    let stale = Filter {
+                        must: vec![Condition::keyword("doc_id", &chunk.doc_id)],
+                        must_not: vec![Condition::keyword("content_hash", &chunk.content_hash)],
+                        ..Default::default()
+                    };
+                    let legacy = Filter {
+                        must: vec![
+                            Condition::empty("doc_id"),
+                            Condition::keyword("url", &chunk.url),
+                            Condition::keyword("title", &chunk.title),
+                        ],
+                        ..Default::default()
+                    };
+                    [stale.into(), legacy.into()]
+                })
+                .collect(),
+            ..Default::default()
+        };
+        self.delete(collection_name, &filter).await
     }
 
     pub async fn upsert_knowledge(
         &self,
         collection_name: &str,
-        knowledge: KnowledgePayload,
-        embedding: Vec<f32>,
-    ) -> Result<PointsOperationResponse> {
-        let mut payload = Payload::new();
-        trace!("Upserting knowledge: {:?}", &knowledge.title);
-        payload.insert("title", knowledge.title);
-        payload.insert("content", knowledge.content);
-        payload.insert("url", knowledge.url);
-        let point = PointStruct::new(Uuid::new_v4().to_string(), embedding, payload);
-        self.upsert_points(collection_name, [point].to_vec(), None)
-            .await
+        chunks: Vec<(KnowledgeChunk, Vec<f32>)>,
+    ) -> Result<()> {
+        let points = chunks
+            .into_iter()
+            .map(|(chunk, embedding)| {
+                trace!(
+                    "Upserting knowledge: {:?} #{}",
+                    &chunk.title,
+                    chunk.chunk_index
+                );
+                VectorPoint {
+                    id: point_id(&chunk.doc_id, chunk.chunk_index),
+                    vector: embedding,
+                    payload: chunk.into(),
+                }
+            })
+            .collect();
+        self.upsert(collection_name, points).await
     }
 }
 
 impl Deref for KnowledgeClient {
-    type Target = QdrantClient;
+    type Target = dyn VectorStore;
 
     fn deref(&self) -> &Self::Target {
-        &self.client
+        self.store.as_ref()
     }
 }
 
-pub async fn upsert_knowledge(qdrant_url: &str, file: PathBuf, collection: &str) -> Result<()> {
-    let qdrant_client = KnowledgeClient::new(qdrant_url).await?;
-    let openai_client = OpenAIClient::new();
+/// Embed a batch of chunks with one request. If the request fails, the chunks are embedded one by
+/// one, so a single bad chunk doesn't fail the whole batch.
+async fn embed_chunks(
+    embedding_provider: &dyn EmbeddingProvider,
+    chunks: Vec<KnowledgeChunk>,
+) -> (
+    Vec<(KnowledgeChunk, Vec<f32>)>,
+    Vec<(KnowledgeChunk, String)>,
+) {
+    let texts: Vec<String> = chunks.iter().map(|x| x.content.clone()).collect();
+    match embedding_provider.embeddings(&texts).await {
+        Ok(embeddings) => return (chunks.into_iter().zip(embeddings).collect(), vec![]),
+        Err(why) => warn!("Embedding a batch failed: {:?}, retry one by one", why),
+    }
+
+    let mut embedded = Vec::new();
+    let mut failed = Vec::new();
+    for chunk in chunks {
+        match embedding_provider.embedding(&chunk.content).await {
+            Ok(embedding) => embedded.push((chunk, embedding)),
+            Err(why) => failed.push((chunk, why.to_string())),
+        }
+    }
+    (embedded, failed)
+}
 
-    match qdrant_client.create_knowledge_collection(collection).await {
-        Ok(Some(response)) => info!("Creating collection operation response: {:?}", response),
-        Ok(None) => info!("Collection {} already exists", collection),
+/// How many documents of a batch were stored or left alone, the documents which failed, and the
+/// tokens of the chunks embedded.
+#[derive(Debug, Default)]
+struct IngestSummary {
+    ingested: usize,
+    unchanged: usize,
+    failed: Vec<(String, String)>,
+    embedded_tokens: u64,
+}
+
+/// Chunk, embed and upsert a batch of documents, replacing the chunks of older versions. With
+/// `incremental`, documents whose stored chunks already have the current content hash are skipped.
+async fn ingest_documents(
+    knowledge_client: &KnowledgeClient,
+    embedding_provider: &dyn EmbeddingProvider,
+    encoder: &TokenEncoder,
+    collection: &str,
+    documents: Vec<KnowledgePayload>,
+    chunk_config: ChunkConfig,
+    incremental: bool,
+) -> IngestSummary {
+    let mut summary = IngestSummary::default();
+    let mut documents: Vec<Vec<KnowledgeChunk>> = documents
+        .iter()
+        .map(|x| chunk_document(x, encoder, chunk_config))
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    if incremental {
+        let doc_ids: Vec<String> = documents.iter().map(|x| x[0].doc_id.clone()).collect();
+        match knowledge_client.document_hashes(collection, &doc_ids).await {
+            Ok(stored) => documents.retain(|chunks| {
+                let unchanged = stored.get(&chunks[0].doc_id).is_some_and(|hashes| {
+                    hashes.len() == chunks.len()
+                        && hashes.iter().all(|x| x == &chunks[0].content_hash)
+                });
+                if unchanged {
+                    trace!("Unchanged: {}", chunks[0].doc_id);
+                    summary.unchanged += 1;
+                }
+                !unchanged
+            }),
+            Err(why) => warn!("Reading stored documents failed: {:?}, update all", why),
+        }
+    }
+    let heads: Vec<KnowledgeChunk> = documents.iter().map(|x| x[0].clone()).collect();
+    let chunks: Vec<KnowledgeChunk> = documents.into_iter().flatten().collect();
+
+    // A document fails as a whole if any of its chunks fails.
+    let mut failed: HashMap<String, (String, String)> = HashMap::new();
+    let mut embedded = Vec::new();
+    let mut chunks = chunks.into_iter().peekable();
+    while chunks.peek().is_some() {
+        let batch: Vec<KnowledgeChunk> = chunks.by_ref().take(INGEST_BATCH_SIZE).collect();
+        let (mut batch_embedded, batch_failed) = embed_chunks(embedding_provider, batch).await;
+        summary.embedded_tokens += batch_embedded
+            .iter()
+            .map(|x| encoder.0.encode_with_special_tokens(&x.0.content).len() as u64)
+            .sum::<u64>();
+        embedded.append(&mut batch_embedded);
+        for (chunk, why) in batch_failed {
+            failed.insert(chunk.doc_id, (chunk.title, why));
+        }
+    }
+    embedded.retain(|x| !failed.contains_key(&x.0.doc_id));
+
+    let mut embedded = embedded.into_iter().peekable();
+    while embedded.peek().is_some() {
+        let batch: Vec<(KnowledgeChunk, Vec<f32>)> =
+            embedded.by_ref().take(UPSERT_BATCH_SIZE).collect();
+        let titles: Vec<(String, String)> = batch
+            .iter()
+            .map(|x| (x.0.doc_id.clone(), x.0.title.clone()))
+            .collect();
+        match knowledge_client.upsert_knowledge(collection, batch).await {
+            Ok(()) => info!("Upserted {} chunks", titles.len()),
+            Err(why) => {
+                error!("Upsert a batch failed: {:?}", why);
+                for (doc_id, title) in titles {
+                    failed.insert(doc_id, (title, why.to_string()));
+                }
+            }
+        }
+    }
+
+    // Only documents which were stored completely replace their older chunks.
+    let stored: Vec<KnowledgeChunk> = heads
+        .into_iter()
+        .filter(|x| !failed.contains_key(&x.doc_id))
+        .collect();
+    if !stored.is_empty() {
+        if let Err(why) = knowledge_client
+            .delete_stale_chunks(collection, &stored)
+            .await
+        {
+            warn!("Deleting stale chunks failed: {:?}", why);
+        }
+    }
+
+    summary.ingested = stored.len();
+    summary.failed = failed.into_values().collect();
+    summary
+}
+
+/// Store the `loaded` documents in `collection`, keeping the tokens embedded in `usage_store`.
+pub async fn upsert_knowledge(
+    knowledge_client: &KnowledgeClient,
+    embedding_provider: &dyn EmbeddingProvider,
+    usage_store: &UsageStore,
+    loaded: LoadedDocuments,
+    collection: &str,
+    chunk_config: ChunkConfig,
+    incremental: bool,
+) -> Result<()> {
+    match knowledge_client
+        .create_knowledge_collection(collection)
+        .await
+    {
+        Ok(true) => info!("Created collection {}", collection),
+        Ok(false) => info!("Collection {} already exists", collection),
         Err(why) => {
             error!("Collection {} creation failed: {:?}", collection, why);
             return Ok(());
         }
     }
 
-    // Load JSON content from file
-    info!("Loading data from {:?}", &file);
-    let text = std::fs::read_to_string(file)?;
-    let raw_payload: KnowledgePayload = serde_json::from_str(&text)?;
-    let content = raw_payload.content.clone();
+    let skipped = loaded.skipped;
+    let mut failed = loaded.failed;
+    let mut ingested = 0;
+    let mut unchanged = 0;
 
-    // Get embedding from openai
-    let requset = CreateEmbeddingRequestArgs::default()
-        .model("text-embedding-ada-002")
-        .input(content)
-        .build()?;
-    let mut response = openai_client.embeddings().create(requset).await?;
-    if let Some(data) = response.data.pop() {
-        info!("Get embedding length: {:?}", data.embedding.len());
+    let count = knowledge_client.count(collection, None).await?;
+    info!("Current count in collection: {:?}", count);
 
-        let count_request = CountPoints {
-            collection_name: collection.into(),
-            filter: None,
-            exact: Some(true),
+    // Chunk, embed and upsert the documents batch by batch
+    let encoder = TokenEncoder::new()?;
+    let mut documents = loaded.documents.into_iter().peekable();
+    while documents.peek().is_some() {
+        let batch: Vec<KnowledgePayload> = documents.by_ref().take(INGEST_BATCH_SIZE).collect();
+        let mut summary = ingest_documents(
+            knowledge_client,
+            embedding_provider,
+            &encoder,
+            collection,
+            batch,
+            chunk_config,
+            incremental,
+        )
+        .await;
+        // The embedding models of OpenAI share the tokenizer of the chat models.
+        let record = UsageRecord {
+            kind: RequestKind::Ingest,
+            model: embedding_provider.model().into(),
+            usage: TokenUsage {
+                prompt_tokens: summary.embedded_tokens,
+                completion_tokens: 0,
+            },
+            origin: None,
         };
-        let count = qdrant_client
-            .count(&count_request)
-            .await?
-            .result
-            .ok_or_else(|| anyhow!("No result"))?
-            .count;
-        info!("Current count in collection: {:?}", count);
+        if let Err(why) = usage_store.record(&record) {
+            warn!("Keeping token usage failed: {:?}", why);
+        }
+        ingested += summary.ingested;
+        unchanged += summary.unchanged;
+        failed.append(&mut summary.failed);
+    }
 
-        let response = qdrant_client
-            .upsert_knowledge(collection, raw_payload, data.embedding)
-            .await?;
-        info!("Upsert response: {:?}", response);
+    println!(
+        "Ingested: {}, unchanged: {}, skipped: {}, failed: {}",
+        ingested,
+        unchanged,
+        skipped.len(),
+        failed.len()
+    );
+    for (source, reason) in skipped.iter() {
+        println!("  skipped {}: {}", source, reason);
+    }
+    for (source, why) in failed.iter() {
+        println!("  failed {}: {}", source, why);
     }
 
     Ok(())
 }
 
-pub async fn query(qdrant_url: &str, question: &str, collection_name: &str) -> Result<()> {
-    let qdrant_client = KnowledgeClient::new(qdrant_url).await?;
-    let openai_client = OpenAIClient::new();
-
-    let request = CreateEmbeddingRequestArgs::default()
-        .model("text-embedding-ada-002")
-        .input(question)
-        .build()?;
-    let mut response = openai_client.embeddings().create(request).await?;
-    if let Some(data) = response.data.pop() {
-        info!("Get embedding length: {:?}", data.embedding.len());
-        let response = qdrant_client
-            .query_knowledge(collection_name, data.embedding, None)
-            .await?;
-        info!("{:?}", response);
-    }
+pub async fn query(
+    knowledge_client: &KnowledgeClient,
+    embedding_provider: &dyn EmbeddingProvider,
+    question: &str,
+    collection_name: &str,
+) -> Result<()> {
+    let embedding = embedding_provider.embedding(question).await?;
+    info!("Get embedding length: {:?}", embedding.len());
+    let response = knowledge_client
+        .query_knowledge(
+            collection_name,
+            embedding,
+            None,
+            RetrievalConfig::default().top_k,
+        )
+        .await?;
+    info!("{:?}", response);
     Ok(())
 }
 
-pub async fn clear_collection(qdrant_url: &str, collection_name: &str) -> Result<()> {
-    let qdrant_client = KnowledgeClient::new(qdrant_url).await?;
-    let response = qdrant_client.delete_collection(collection_name).await?;
-    info!("Clear collection response: {:?}", response);
+pub async fn clear_collection(
+    knowledge_client: &KnowledgeClient,
+    collection_name: &str,
+) -> Result<()> {
+    knowledge_client.delete_collection(collection_name).await?;
+    info!("Cleared collection {}", collection_name);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use super::{
+        content_hash, group_chunks, ingest_documents, point_id, Health, KnowledgeChunk,
+        KnowledgeClient, KnowledgePayload, EMBEDDING_SIZE,
+    };
+    use crate::{
+        ai::{EmbeddingProvider, TokenEncoder},
+        chunker::ChunkConfig,
+        sqlite_vector_store::SqliteVectorStore,
+        vector_store::{Condition, Filter, Payload, VectorPoint},
+    };
+
+    /// Embeds a text into the axis of its first letter, so texts with the same first letter are
+    /// close.
+    struct LetterEmbeddings;
+
+    #[async_trait]
+    impl EmbeddingProvider for LetterEmbeddings {
+        async fn embedding(&self, text: &str) -> Result<Vec<f32>> {
+            let mut embedding = vec![0.0; EMBEDDING_SIZE as usize];
+            embedding[text.bytes().next().unwrap_or_default() as usize] = 1.0;
+            Ok(embedding)
+        }
+
+        async fn embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+            let mut embeddings = Vec::new();
+            for text in texts {
+                embeddings.push(self.embedding(text).await?);
+            }
+            Ok(embeddings)
+        }
+
+        fn model(&self) -> &str {
+            "letters"
+        }
+    }
+
+    fn document(id: &str, content: &str) -> KnowledgePayload {
+        KnowledgePayload {
+            id: Some(id.into()),
+            url: format!("https://{}", id),
+            title: id.to_uppercase(),
+            content: content.into(),
+        }
+    }
+
+    fn chunk(doc_id: &str, chunk_index: i64, content: &str) -> KnowledgeChunk {
+        KnowledgeChunk {
+            doc_id: doc_id.into(),
+            chunk_index,
+            content_hash: String::new(),
+            title: doc_id.to_uppercase(),
+            url: String::new(),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn test_group_chunks_by_document() {
+        let chunks = vec![
+            chunk("b", 1, "two\n\nthree"),
+            chunk("a", 0, "alpha"),
+            chunk("b", 0, "one\n\ntwo"),
+            chunk("b", 3, "five"),
+        ];
+        let documents = group_chunks(chunks);
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].title, "B");
+        assert_eq!(documents[0].content, "one\n\ntwo\n\nthree\n\n…\n\nfive");
+        assert_eq!(documents[1].content, "alpha");
+    }
+
+    #[test]
+    fn test_point_ids_and_hashes_are_stable() {
+        assert_eq!(point_id("https://a", 0), point_id("https://a", 0));
+        assert_ne!(point_id("https://a", 0), point_id("https://a", 1));
+
+        let mut document = KnowledgePayload {
+            id: None,
+            url: "https://a".into(),
+            title: "A".into(),
+            content: "alpha".into(),
+        };
+        let config = ChunkConfig::default();
+        let hash = content_hash(&document, config);
+        assert_eq!(hash, content_hash(&document.clone(), config));
+        assert_eq!(document.document_id(), "https://a");
+
+        document.content.push('!');
+        assert_ne!(hash, content_hash(&document, config));
+    }
+
+    #[tokio::test]
+    async fn test_ingest_into_embedded_store() {
+        let client = KnowledgeClient::new(Arc::new(SqliteVectorStore::open(":memory:").unwrap()));
+        assert!(client.create_knowledge_collection("kb").await.unwrap());
+        assert!(!client.create_knowledge_collection("kb").await.unwrap());
+
+        // A point stored before documents were chunked, which the new version of "a" replaces.
+        let mut payload = Payload::new();
+        payload.insert("url".into(), "https://a".into());
+        payload.insert("title".into(), "A".into());
+        payload.insert("content".into(), "apples".into());
+        let legacy = VectorPoint {
+            id: point_id("legacy", 0),
+            vector: LetterEmbeddings.embedding("apples").await.unwrap(),
+            payload,
+        };
+        client.upsert("kb", vec![legacy]).await.unwrap();
+
+        let encoder = TokenEncoder::new().unwrap();
+        let config = ChunkConfig::default();
+        let documents = vec![
+            document("a", "apples are red"),
+            document("b", "bananas are yellow"),
+        ];
+        let summary = ingest_documents(
+            &client,
+            &LetterEmbeddings,
+            &encoder,
+            "kb",
+            documents,
+            config,
+            true,
+        )
+        .await;
+        assert_eq!((summary.ingested, summary.unchanged), (2, 0));
+        let embedded_tokens = summary.embedded_tokens;
+        assert!(embedded_tokens > 0);
+        assert_eq!(client.count("kb", None).await.unwrap(), 2);
+
+        let embedding = LetterEmbeddings.embedding("bananas?").await.unwrap();
+        let found = client
+            .query_knowledge("kb", embedding, Some(0.5), 3)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "B");
+
+        // A changed document replaces its chunks, an unchanged one is left alone.
+        let documents = vec![
+            document("a", "avocados are green"),
+            document("b", "bananas are yellow"),
+        ];
+        let summary = ingest_documents(
+            &client,
+            &LetterEmbeddings,
+            &encoder,
+            "kb",
+            documents,
+            config,
+            true,
+        )
+        .await;
+        assert_eq!((summary.ingested, summary.unchanged), (1, 1));
+        assert!(summary.embedded_tokens > 0 && summary.embedded_tokens < embedded_tokens);
+        let chunks = client.scroll_chunks("kb", None).await.unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().any(|x| x.content == "avocados are green"));
+        let filter = Filter {
+            must: vec![Condition::keyword("doc_id", "a")],
+            ..Default::default()
+        };
+        assert_eq!(client.count("kb", Some(&filter)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_qdrant_is_degraded() {
+        let health = Health::default();
+        let since = UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(health.mark_down(since));
+        assert!(!health.mark_down(since + Duration::from_secs(5)));
+        assert_eq!(health.down_since(), Some(since));
+        assert!(health.mark_up());
+        assert!(!health.mark_up());
+
+        // Nothing listens on port 1, the client is created anyway.
+        let client = KnowledgeClient::qdrant("http://127.0.0.1:1").await.unwrap();
+        assert!(client.is_available());
+        client.watch_health();
+        for _ in 0..50 {
+            if !client.is_available() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(client.down_since().is_some());
+    }
+}
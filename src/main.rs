@@ -1,7 +1,27 @@
+pub mod backup;
+pub mod chunker;
 pub mod command_handler;
+pub mod config;
 pub mod conversation;
+pub mod failure;
 pub mod helper;
+pub mod ingest;
+pub mod kb;
+pub mod memory;
+pub mod models;
 pub mod msg_handler;
+pub mod qdrant_store;
+pub mod rate_limit;
+pub mod reply_chain;
+pub mod retrieval;
+pub mod retry;
+pub mod slash_command;
+pub mod splitter;
+pub mod sqlite_store;
+pub mod sqlite_vector_store;
+pub mod trigger;
+pub mod usage;
+pub mod vector_store;
 pub mod knowledge_base;
 pub mod ai;
 
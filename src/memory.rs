@@ -0,0 +1,136 @@
+use std::str::FromStr;
+
+use async_openai::types::ChatCompletionRequestMessage;
+
+use crate::{conversation::ConversationCtx, models::GenerationConfig};
+
+/// Instructions of the chat call condensing the oldest messages of a conversation.
+const SUMMARY_PROMPT: &str = "Condense the conversation below into a short summary for yourself, \
+    to continue it later. Keep the facts, names, preferences, decisions and open questions, \
+    and merge in the summary of the conversation before, if there is one. Answer with the \
+    summary only, in the language of the conversation.";
+
+/// Messages which are always kept as they are when a conversation is summarized, so the last
+/// exchanges aren't paraphrased.
+pub const KEEP_RECENT_MESSAGES: usize = 4;
+
+/// Tokens a summary may take at most.
+const SUMMARY_MAX_TOKENS: u16 = 400;
+
+/// How the history of a conversation is kept within the context of the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMode {
+    /// Drop the oldest messages.
+    Window,
+    /// Condense the oldest messages into a summary once the history grows too long.
+    Summary,
+}
+
+impl FromStr for MemoryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "window" => Ok(Self::Window),
+            "summary" => Ok(Self::Summary),
+            _ => Err(format!("Unknown memory mode: {}", s)),
+        }
+    }
+}
+
+/// The system prompt of a conversation, followed by the summary of its earlier messages.
+pub fn with_summary(system_prompt: &str, summary: Option<&str>) -> String {
+    match summary {
+        Some(summary) => format!(
+            "{}\n\nSummary of the conversation so far:\n{}",
+            system_prompt, summary
+        ),
+        None => system_prompt.into(),
+    }
+}
+
+/// Number of the oldest messages of `history` to condense, when it takes `tokens` and it's
+/// summarized beyond `threshold` tokens or `max_messages` messages, before the oldest are
+/// dropped.
+pub fn messages_to_summarize(
+    history: &ConversationCtx,
+    tokens: usize,
+    threshold: usize,
+    max_messages: usize,
+) -> usize {
+    // Summarize before the store drops the oldest messages of the next exchange.
+    let full = history.len() + 2 > max_messages;
+    match tokens > threshold || full {
+        true => history.len().saturating_sub(KEEP_RECENT_MESSAGES),
+        false => 0,
+    }
+}
+
+/// The conversation asking the model to condense `messages` and the `summary` before them.
+pub fn summary_request(
+    summary: Option<&str>,
+    messages: &[ChatCompletionRequestMessage],
+) -> ConversationCtx {
+    let mut transcript = String::new();
+    if let Some(summary) = summary {
+        transcript.push_str(&format!("Summary so far:\n{}\n\n", summary));
+    }
+    transcript.push_str("Conversation:\n");
+    for message in messages {
+        let speaker = match &message.name {
+            Some(name) => name.clone(),
+            None => message.role.to_string(),
+        };
+        transcript.push_str(&format!("{}: {}\n", speaker, message.content));
+    }
+
+    let mut request = ConversationCtx::default();
+    request
+        .add_system_message(SUMMARY_PROMPT, None)
+        .add_user_message(&transcript, None);
+    request
+}
+
+/// How a summary is generated: focused, and short enough to leave room for the conversation.
+pub fn summary_generation() -> GenerationConfig {
+    GenerationConfig {
+        temperature: Some(0.2),
+        max_tokens: Some(SUMMARY_MAX_TOKENS),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{messages_to_summarize, summary_request, with_summary, KEEP_RECENT_MESSAGES};
+    use crate::conversation::ConversationCtx;
+
+    #[test]
+    fn test_summary_request() {
+        let mut history = ConversationCtx::default();
+        for i in 0..6 {
+            history
+                .add_user_message(&format!("Question {}", i), Some("alice".into()))
+                .add_assistant_message(&format!("Answer {}", i), None);
+        }
+
+        assert_eq!(messages_to_summarize(&history, 500, 1000, 20), 0);
+        assert_eq!(
+            messages_to_summarize(&history, 1500, 1000, 20),
+            12 - KEEP_RECENT_MESSAGES
+        );
+        // A history about to be cut by the store is summarized before.
+        assert_eq!(messages_to_summarize(&history, 500, 1000, 13), 8);
+
+        let messages: Vec<_> = history.iter().take(2).cloned().collect();
+        let request = summary_request(Some("Alice likes tea."), &messages);
+        assert_eq!(request.len(), 2);
+        assert_eq!(
+            request[1].content,
+            "Summary so far:\nAlice likes tea.\n\nConversation:\nalice: Question 0\nassistant: Answer 0\n"
+        );
+
+        assert_eq!(with_summary("Be nice.", None), "Be nice.");
+        assert!(with_summary("Be nice.", Some("Alice likes tea.")).ends_with("Alice likes tea."));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use tiktoken_rs::tiktoken::{cl100k_base, p50k_base, r50k_base, CoreBPE};
+
+/// Chat model of the channels which don't configure their own.
+pub const DEFAULT_CHAT_MODEL: &str = "gpt-3.5-turbo";
+/// Embedding model of the knowledge bases.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+/// Tokens kept free for the answer of channels which don't configure `max_tokens`.
+pub const DEFAULT_COMPLETION_TOKENS: usize = 512;
+
+/// The byte pair encoding a model splits text into tokens with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    Cl100kBase,
+    P50kBase,
+    R50kBase,
+}
+
+impl Tokenizer {
+    pub fn encoding(&self) -> Result<CoreBPE> {
+        match self {
+            Tokenizer::Cl100kBase => cl100k_base(),
+            Tokenizer::P50kBase => p50k_base(),
+            Tokenizer::R50kBase => r50k_base(),
+        }
+    }
+}
+
+/// What the bot needs to know about a chat model to fit a conversation into it. Check the
+/// overheads of the OpenAI models here:
+/// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSpec {
+    /// Tokens of the prompt and the answer together.
+    pub context_window: usize,
+    pub tokenizer: Tokenizer,
+    /// Tokens every message takes besides its content, role and name.
+    pub tokens_per_message: usize,
+    /// Tokens a name takes besides its content, negative if it replaces the role.
+    pub tokens_per_name: i64,
+    /// Tokens every answer is primed with.
+    pub tokens_per_reply: usize,
+    /// Tokens the model generates at most, if less than its context.
+    pub max_output_tokens: Option<usize>,
+}
+
+impl Default for ModelSpec {
+    /// The spec assumed for unknown models.
+    fn default() -> Self {
+        spec(4096, 3, 1, 3)
+    }
+}
+
+const fn spec(
+    context_window: usize,
+    tokens_per_message: usize,
+    tokens_per_name: i64,
+    tokens_per_reply: usize,
+) -> ModelSpec {
+    ModelSpec {
+        context_window,
+        tokenizer: Tokenizer::Cl100kBase,
+        tokens_per_message,
+        tokens_per_name,
+        tokens_per_reply,
+        max_output_tokens: None,
+    }
+}
+
+/// Models known without configuration. A model matches the longest name it starts with, so
+/// snapshots like "gpt-4-0613" share the spec of their model.
+const MODELS: &[(&str, ModelSpec)] = &[
+    ("gpt-3.5-turbo", spec(4096, 4, -1, 2)),
+    ("gpt-3.5-turbo-0613", spec(4096, 3, 1, 3)),
+    ("gpt-3.5-turbo-16k", spec(16384, 3, 1, 3)),
+    ("gpt-4", spec(8192, 3, 1, 3)),
+    ("gpt-4-32k", spec(32768, 3, 1, 3)),
+];
+
+/// The spec of `model`, looked up in the `custom` specs of the config before the known models.
+pub fn lookup(model: &str, custom: &HashMap<String, ModelSpec>) -> Option<ModelSpec> {
+    if let Some(spec) = custom.get(model) {
+        return Some(spec.clone());
+    }
+    MODELS
+        .iter()
+        .filter(|(name, _)| model.starts_with(name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, spec)| spec.clone())
+}
+
+impl ModelSpec {
+    /// Tokens kept free for an answer of at most `max_tokens`.
+    pub fn completion_tokens(&self, max_tokens: Option<u16>) -> usize {
+        let tokens = max_tokens.map_or(DEFAULT_COMPLETION_TOKENS, usize::from);
+        match self.max_output_tokens {
+            Some(max_output_tokens) => tokens.min(max_output_tokens),
+            None => tokens,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.context_window == 0 {
+            return Err(anyhow!("context_window is 0"));
+        }
+        if self.max_output_tokens == Some(0) {
+            return Err(anyhow!("max_output_tokens is 0"));
+        }
+        Ok(())
+    }
+}
+
+/// How a model generates answers. Parameters which are left out use the defaults of the api.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationConfig {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Tokens an answer may take at most.
+    pub max_tokens: Option<u16>,
+    /// Up to 4 sequences the model stops generating at.
+    pub stop: Vec<String>,
+}
+
+impl GenerationConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(anyhow!(
+                    "temperature {} is not between 0 and 2",
+                    temperature
+                ));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(anyhow!("top_p {} is not between 0 and 1", top_p));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(anyhow!("max_tokens is 0"));
+        }
+        if self.stop.len() > 4 {
+            return Err(anyhow!("{} stop sequences, at most 4", self.stop.len()));
+        }
+        if self.stop.iter().any(|x| x.is_empty()) {
+            return Err(anyhow!("stop sequence is empty"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{lookup, GenerationConfig, ModelSpec};
+
+    #[test]
+    fn test_lookup_model() {
+        let custom = HashMap::new();
+        assert_eq!(lookup("gpt-4-0613", &custom).unwrap().context_window, 8192);
+        assert_eq!(
+            lookup("gpt-4-32k-0613", &custom).unwrap().context_window,
+            32768
+        );
+        assert_eq!(
+            lookup("gpt-3.5-turbo-16k-0613", &custom)
+                .unwrap()
+                .context_window,
+            16384
+        );
+        assert_eq!(
+            lookup("gpt-3.5-turbo-0301", &custom)
+                .unwrap()
+                .tokens_per_name,
+            -1
+        );
+        assert_eq!(lookup("llama-2-13b", &custom), None);
+
+        let llama = ModelSpec {
+            context_window: 2048,
+            max_output_tokens: Some(256),
+            ..Default::default()
+        };
+        let custom = HashMap::from([("llama-2-13b".to_string(), llama.clone())]);
+        assert_eq!(lookup("llama-2-13b", &custom), Some(llama.clone()));
+        assert_eq!(llama.completion_tokens(None), 256);
+        assert_eq!(llama.completion_tokens(Some(100)), 100);
+
+        let generation = GenerationConfig {
+            temperature: Some(2.5),
+            ..Default::default()
+        };
+        assert!(generation.validate().is_err());
+        let generation = GenerationConfig {
+            stop: vec!["a", "b", "c", "d", "e"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..Default::default()
+        };
+        assert!(generation.validate().is_err());
+    }
+}
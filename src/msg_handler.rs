@@ -1,27 +1,76 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
 use async_openai::types::{ChatCompletionRequestMessage, Role};
+use futures::StreamExt;
 use log_error::LogError;
 use serenity::{
     async_trait,
-    model::{channel::Message, gateway::Ready, prelude::UserId},
+    model::{
+        application::interaction::Interaction,
+        channel::{Channel, ChannelType, Message},
+        gateway::Ready,
+        prelude::{ChannelId, GuildId, UserId},
+    },
     prelude::*,
 };
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 use crate::{
-    ai::{Openai, CHAT_GPT_LIMIT},
-    conversation::{ConversationCache, ConversationCtx},
+    ai::{ChatProvider, ChatResponse, EmbeddingProvider, TokenEncoder, TokenEncoders, TokenUsage},
+    config::{ChannelSettings, ConfigHandle, TriggerMode},
+    conversation::{
+        participant_name, ConversationCtx, ConversationKey, ConversationScope, ConversationStore,
+        MessageOrigin, MAX_CONVERSATION_LENGTH,
+    },
+    failure::{AnswerError, CorrelationId},
     helper::try_log,
-    knowledge_base::{KnowledgeClient, KnowledgePayload},
+    knowledge_base::KnowledgeClient,
+    memory::{
+        messages_to_summarize, summary_generation, summary_request, with_summary, MemoryMode,
+    },
+    rate_limit::RateLimiter,
+    reply_chain::{conversation_from_chain, fetch_reply_chain},
+    retrieval::{format_passages, pack_passages, with_sources, Passage, PassageCache},
+    slash_command::{register_commands, tell_failure},
+    splitter::{split_message, DISCORD_MESSAGE_LIMIT},
+    trigger::question_for,
+    usage::{RequestKind, UsageRecord, UsageStore},
 };
 
+/// Content of the reply before the first tokens of a streamed answer arrive.
+const STREAM_PLACEHOLDER: &str = "…";
+/// Minimal interval between two edits of a streamed reply, to stay under discord rate limits.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+/// Appended to a streamed answer which broke off.
+const STREAM_INTERRUPTED: &str = "(answer interrupted)";
+
 pub struct Handler {
-    pub openai_client: Openai,
-    pub conversation_cache: ConversationCache,
+    pub chat_provider: Arc<dyn ChatProvider>,
+    pub embedding_provider: Arc<dyn EmbeddingProvider>,
+    pub token_encoder: TokenEncoder,
+    /// Encoders of the chat models, which may split text differently than `token_encoder`.
+    pub token_encoders: TokenEncoders,
+    pub conversation_store: Box<dyn ConversationStore>,
+    pub conversation_scope: ConversationScope,
+    pub memory: MemoryMode,
+    /// Tokens of the history of a conversation beyond which its oldest messages are
+    /// summarized, in the `Summary` memory mode.
+    pub summary_threshold: usize,
     pub knowledge_client: KnowledgeClient,
-    pub collection_name: String,
+    /// Settings given on the command line, which the config file overrides per guild and
+    /// channel.
+    pub settings: ChannelSettings,
+    pub config: ConfigHandle,
+    /// Knowledge given with the last answer of each conversation, shown by `/sources`.
+    pub last_passages: PassageCache,
+    pub stream_replies: bool,
+    pub rate_limiter: RateLimiter,
+    pub usage_store: UsageStore,
 }
 
 #[async_trait]
@@ -32,144 +81,572 @@ impl EventHandler for Handler {
     // Event handlers are dispatched through a threadpool, and so multiple
     // events can be dispatched simultaneously.
     async fn message(&self, ctx: Context, msg: Message) {
-        try_log!(self._message(ctx, msg).await)
+        let id = CorrelationId::new();
+        let span = info_span!("message", id = %id);
+        let (channel_id, message_id) = (msg.channel_id, msg.id);
+        if let Err(why) = self
+            ._message(ctx.clone(), msg)
+            .instrument(span.clone())
+            .await
+        {
+            let _entered = span.enter();
+            error!("Answering failed [{}]: {}", id, why);
+            // Tell the user the question failed, instead of leaving it unanswered.
+            channel_id
+                .send_message(&ctx.http, |m| {
+                    m.content(why.reply(&id))
+                        .reference_message((channel_id, message_id))
+                })
+                .await
+                .log_error("Reply failure failed");
+        }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+        try_log!(register_commands(&ctx).await)
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let command = match interaction {
+            Interaction::ApplicationCommand(command) => command,
+            _ => return,
+        };
+        let id = CorrelationId::new();
+        let span = info_span!("command", id = %id);
+        if let Err(why) = self
+            ._interaction(&ctx, &command)
+            .instrument(span.clone())
+            .await
+        {
+            let _entered = span.enter();
+            error!("Command /{} failed [{}]: {}", command.data.name, id, why);
+            tell_failure(&ctx, &command, &why.reply(&id)).await;
+        }
     }
 }
 
 impl Handler {
-    fn extract_legal_content(bot_user_id: UserId, msg: &Message) -> Option<&str> {
-        let mention_part = String::from("<@") + &bot_user_id.0.to_string() + ">";
-        if !msg.content.starts_with(&mention_part) {
-            return None;
-        }
-        let index = msg.content.find('>').unwrap_or(0);
-        if index + 1 > msg.content.len() - 2 {
-            return None;
-        }
-        let real_content = &msg.content[index + 2..];
-        Some(real_content)
+    /// Key of the conversation a message of `user_id` in `channel_id` belongs to.
+    pub(crate) async fn conversation_key(
+        &self,
+        ctx: &Context,
+        user_id: UserId,
+        channel_id: ChannelId,
+    ) -> Result<ConversationKey> {
+        let in_thread = match self.conversation_scope {
+            ConversationScope::Thread => matches!(
+                channel_id.to_channel(ctx).await?,
+                Channel::Guild(channel) if matches!(
+                    channel.kind,
+                    ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+                )
+            ),
+            _ => false,
+        };
+        Ok(self.conversation_scope.key(user_id, channel_id, in_thread))
+    }
+
+    /// The settings which apply to messages in `channel_id`.
+    pub(crate) fn settings_for(
+        &self,
+        guild_id: Option<GuildId>,
+        channel_id: ChannelId,
+    ) -> ChannelSettings {
+        self.config
+            .get()
+            .resolve(&self.settings, guild_id, channel_id)
     }
 
-    fn build_conversation(&self, user_id: UserId) -> Result<ConversationCtx> {
+    /// The system prompt followed by `history`, or by the stored history of the conversation.
+    fn build_conversation(
+        &self,
+        settings: &ChannelSettings,
+        key: ConversationKey,
+        history: Option<ConversationCtx>,
+    ) -> Result<ConversationCtx> {
+        let history = match history {
+            Some(history) => history,
+            None => self.conversation_store.get_messages(key)?,
+        };
         let mut conversation = ConversationCtx::default();
         conversation.add_system_message(
-            "I will ask with format like this:
-        Question: {text}
-        Knowledge: {text}
-        You are a helpful assistant, and you should answer question after the 'Question'.
-        And there may be related knowledge after knowledge you could refer to. ",
+            &with_summary(&settings.system_message(), history.summary.as_deref()),
             None,
         );
-
-        let history: VecDeque<ChatCompletionRequestMessage> =
-            self.conversation_cache.get_messages(user_id)?.into();
+        let history: VecDeque<ChatCompletionRequestMessage> = history.into();
         conversation.extend(history);
         Ok(conversation)
     }
 
-    pub async fn query_knowledge(&self, embedding: Vec<f32>) -> Result<KnowledgePayload> {
-        let mut response = self
+    /// The most related passages which fit into the knowledge token budget.
+    pub async fn query_knowledge(
+        &self,
+        settings: &ChannelSettings,
+        embedding: Vec<f32>,
+    ) -> Result<Vec<Passage>> {
+        let response = self
             .knowledge_client
-            .query_knowledge(&self.collection_name, embedding, Some(0.78))
+            .query_knowledge(
+                &settings.collection,
+                embedding,
+                Some(settings.score_threshold),
+                settings.retrieval.top_k,
+            )
             .await?;
-        response.reverse();
+        let passages = pack_passages(
+            &self.token_encoder,
+            response,
+            settings.retrieval.token_budget,
+        );
+        if passages.is_empty() {
+            return Err(anyhow!("No result found"));
+        }
+        Ok(passages)
+    }
 
-        if let Some(response) = response.pop() {
-            Ok(response)
-        } else {
-            Err(anyhow!("No result found"))
+    /// The passages related to `question`, none if there aren't any or the search fails.
+    async fn find_passages(
+        &self,
+        settings: &ChannelSettings,
+        question: &str,
+        origin: MessageOrigin,
+    ) -> Result<Vec<Passage>> {
+        let embedding = self.embedding_provider.embedding(question).await?;
+        // The embedding models of OpenAI share the tokenizer of the chat models.
+        let usage = TokenUsage {
+            prompt_tokens: self
+                .token_encoder
+                .0
+                .encode_with_special_tokens(question)
+                .len() as u64,
+            completion_tokens: 0,
+        };
+        let model = self.embedding_provider.model().to_string();
+        self.record_usage(origin, RequestKind::Embedding, model, usage);
+        match self.query_knowledge(settings, embedding).await {
+            Ok(passages) => Ok(passages),
+            Err(why) => {
+                debug!("No knowledge for the question: {}", why);
+                Ok(Vec::new())
+            }
         }
     }
 
     fn build_conversation_with_knowledge(
         &self,
         mut conversation: ConversationCtx,
-        knowledge: KnowledgePayload,
+        passages: &[Passage],
         question: &str,
+        name: Option<String>,
     ) -> Result<ConversationCtx> {
-        debug!("Knowledge url: {}", &knowledge.url);
-        let context = format!("Question: {}\nKnowledge: {}", question, &knowledge.content);
-        conversation.add_user_message(&context, None);
+        for passage in passages.iter() {
+            debug!("Knowledge [{}] url: {}", passage.number, &passage.url);
+        }
+        let context = format!(
+            "Question: {}\nKnowledge:\n{}",
+            question,
+            format_passages(passages)
+        );
+        conversation.add_user_message(&context, name);
         Ok(conversation)
     }
 
-    async fn _message(&self, ctx: Context, msg: Message) -> Result<()> {
-        match msg.mentions_me(&ctx).await {
-            Err(why) => {
-                error!("Error check mentions_me: {:?}", why);
-                Ok(())
+    /// Build the conversation to complete for `question`: the history of the conversation, or
+    /// `history` if given, the question and the knowledge related to it. Fails with
+    /// `AnswerError::QuestionTooLong` if it doesn't fit into the context of the model.
+    pub(crate) async fn prepare_answer(
+        &self,
+        settings: &ChannelSettings,
+        key: ConversationKey,
+        question: &str,
+        name: Option<String>,
+        history: Option<ConversationCtx>,
+        origin: MessageOrigin,
+    ) -> Result<(ConversationCtx, Vec<Passage>), AnswerError> {
+        let mut conversation = self
+            .build_conversation(settings, key, history)
+            .map_err(AnswerError::Conversation)?;
+        let passages = match self.knowledge_client.is_available() {
+            true => self.find_passages(settings, question, origin).await?,
+            false => {
+                debug!("The vector store is unreachable, answering without knowledge");
+                Vec::new()
             }
-            Ok(false) => {
-                trace!("Content: {:?}", &msg.content);
-                Ok(())
+        };
+        let mut conversation = match passages.is_empty() {
+            false => {
+                self.build_conversation_with_knowledge(conversation, &passages, question, name)?
             }
-            Ok(true) => {
-                info!(
-                    "Mentioned by {:?}, Content: {:?}",
-                    &msg.author.name, &msg.content
-                );
+            true => {
+                conversation.add_user_message(question, name);
+                conversation
+            }
+        };
+
+        // Pruning old message in conversation if it leaves no room for the answer in the
+        // context of the model
+        let model = self.config.get().model_spec(&settings.model);
+        let completion_tokens = model.completion_tokens(settings.generation.max_tokens);
+        if let Err(why) = self
+            .token_encoders
+            .get(model.tokenizer)?
+            .shrink_conversation(&mut conversation, &model, completion_tokens)
+        {
+            warn!(
+                "Shrink conversation failed: {:?}, content: {}",
+                why, question
+            );
+            return Err(AnswerError::QuestionTooLong);
+        }
+        Ok((conversation, passages))
+    }
+
+    /// Tokens of `conversation` as a prompt to the model of `settings`.
+    pub(crate) fn prompt_tokens(
+        &self,
+        settings: &ChannelSettings,
+        conversation: &ConversationCtx,
+    ) -> Result<usize> {
+        let model = self.config.get().model_spec(&settings.model);
+        self.token_encoders
+            .get(model.tokenizer)?
+            .num_tokens_from_messages(&conversation.value, &model)
+    }
+
+    /// Keep the question and its answer in the history of the conversation, and the knowledge
+    /// given with it for `/sources`.
+    pub(crate) fn remember_answer(
+        &self,
+        key: ConversationKey,
+        exchange: [(&str, Option<String>); 2],
+        passages: Vec<Passage>,
+        origin: MessageOrigin,
+    ) {
+        let [(question, name), (answer, _)] = exchange;
+        vec![
+            (Role::User, question, name),
+            (Role::Assistant, answer, None),
+        ]
+        .into_iter()
+        .for_each(|x| {
+            self.conversation_store
+                .add_message(key, x.0, x.1, x.2, origin)
+                .log_error("Cache Conversation failed");
+        });
+        self.last_passages.put(key, passages);
+    }
+
+    /// Condense the oldest messages of the conversation of `key` into its summary, once its
+    /// history outgrows the summary threshold.
+    pub(crate) async fn summarize_history(
+        &self,
+        settings: &ChannelSettings,
+        key: ConversationKey,
+        origin: MessageOrigin,
+    ) -> Result<()> {
+        if self.memory != MemoryMode::Summary {
+            return Ok(());
+        }
+        let history = self.conversation_store.get_messages(key)?;
+        let model = self.config.get().model_spec(&settings.model);
+        let encoder = self.token_encoders.get(model.tokenizer)?;
+        let tokens = encoder.num_tokens_from_messages(&history.value, &model)?;
+        let count = messages_to_summarize(
+            &history,
+            tokens,
+            self.summary_threshold,
+            MAX_CONVERSATION_LENGTH,
+        );
+        if count == 0 {
+            return Ok(());
+        }
+
+        let messages: Vec<ChatCompletionRequestMessage> =
+            history.iter().take(count).cloned().collect();
+        let request = summary_request(history.summary.as_deref(), &messages);
+        let prompt_tokens = encoder.num_tokens_from_messages(&request.value, &model)?;
+        let response = self
+            .chat_provider
+            .chat_complete(&settings.model, &summary_generation(), request)
+            .await?;
+        self.record_chat_usage(origin, &settings.model, prompt_tokens, &response);
+        self.conversation_store
+            .summarize(key, response.content.trim(), count)?;
+        debug!("Summarized {} messages of {}", count, key);
+        Ok(())
+    }
+
+    /// Count the tokens consumed by a request for a question into the daily quotas of its asker,
+    /// and keep them for the usage report in the background.
+    fn record_usage(
+        &self,
+        origin: MessageOrigin,
+        kind: RequestKind,
+        model: String,
+        usage: TokenUsage,
+    ) {
+        self.rate_limiter
+            .record_usage(origin.user_id, origin.guild_id, usage.total())
+            .log_error("Record token usage failed");
+        let record = UsageRecord {
+            kind,
+            model,
+            usage,
+            origin: Some(origin),
+        };
+        let usage_store = self.usage_store.clone();
+        tokio::task::spawn_blocking(move || {
+            usage_store
+                .record(&record)
+                .log_error("Keep token usage failed")
+        });
+    }
+
+    /// Record the tokens consumed by an answer. Answers the api doesn't report the usage of are
+    /// estimated from the `prompt_tokens` of the conversation.
+    pub(crate) fn record_chat_usage(
+        &self,
+        origin: MessageOrigin,
+        model: &str,
+        prompt_tokens: usize,
+        response: &ChatResponse,
+    ) {
+        let usage = response.usage.unwrap_or_else(|| {
+            self.token_encoder
+                .estimate_usage(prompt_tokens, &response.content)
+        });
+        self.record_usage(origin, RequestKind::Chat, model.into(), usage);
+    }
 
-                // Extract question from message
-                let typing = msg.channel_id.start_typing(&ctx.http)?;
-                let real_content =
-                    match Self::extract_legal_content(ctx.cache.current_user_id(), &msg) {
-                        Some(x) => x,
-                        None => return Ok(()),
-                    };
-
-                // Build conversation with atuhor id, and find related knowledge
-                let mut conversation = self.build_conversation(msg.author.id)?;
-                let embedding = self.openai_client.embedding(real_content).await?;
-                let mut conversation = match self.query_knowledge(embedding).await {
-                    Ok(knowledge) => self.build_conversation_with_knowledge(
-                        conversation,
-                        knowledge,
-                        real_content,
-                    )?,
-                    Err(_) => {
-                        conversation.add_user_message(real_content, None);
-                        conversation
-                    }
-                };
-
-                // Pruning old message in conversation if it's exceed the limit of token of openai api
-                if let Err(why) = self
-                    .openai_client
-                    .shrink_conversation(&mut conversation, CHAT_GPT_LIMIT)
-                {
-                    warn!(
-                        "Shrink conversation failed: {:?}, content: {}",
-                        why, real_content
-                    );
-                    let _t = typing.stop();
-                    msg.channel_id
-                        .send_message(&ctx.http, |m| m.content("I apologize, but could you please provide a shorter question? It would be easier for me to assist you if the question is more concise. Thank you!").reference_message(&msg))
+    /// Bring the chain of `replies` to `msg` in line with `content`: parts which changed are
+    /// edited, new parts are sent as a reply to the part before them, and replies beyond the
+    /// last part are deleted.
+    async fn sync_reply_chain(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        replies: &mut Vec<Message>,
+        content: &str,
+    ) -> Result<()> {
+        let parts = split_message(content, DISCORD_MESSAGE_LIMIT);
+        let count = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            match replies.get_mut(i) {
+                Some(reply) if reply.content == part => {}
+                Some(reply) => reply.edit(&ctx.http, |m| m.content(part)).await?,
+                None => {
+                    let reference = replies.last().unwrap_or(msg);
+                    let reply = msg
+                        .channel_id
+                        .send_message(&ctx.http, |m| m.content(part).reference_message(reference))
                         .await?;
+                    replies.push(reply);
+                }
+            }
+        }
+        let extra = replies.split_off(count);
+        self.delete_replies(ctx, &extra).await;
+        Ok(())
+    }
+
+    /// Remove `replies` already sent, such as the placeholder of an answer which failed before
+    /// any of it arrived.
+    async fn delete_replies(&self, ctx: &Context, replies: &[Message]) {
+        for reply in replies {
+            reply
+                .delete(&ctx.http)
+                .await
+                .log_error("Delete reply failed");
+        }
+    }
+
+    /// Reply with a placeholder which is edited while the answer streams in. Falls back to a
+    /// single, non streamed completion when the stream can't be opened. A stream which breaks
+    /// off keeps the answer received so far, marked as interrupted, instead of paying for the
+    /// whole answer again, one which fails before any content arrives removes the placeholder.
+    /// The sources cited from `passages` are added once the answer is complete.
+    async fn send_streaming_reply(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        settings: &ChannelSettings,
+        conversation: ConversationCtx,
+        passages: &[Passage],
+    ) -> Result<ChatResponse> {
+        let (model, generation) = (&settings.model, &settings.generation);
+        let mut replies = Vec::new();
+        let mut stream = match self
+            .chat_provider
+            .chat_complete_stream(model, generation, conversation.clone())
+            .await
+        {
+            Ok(stream) => stream,
+            Err(why) => {
+                warn!(
+                    "Open chat stream failed: {:?}, fall back to single reply",
+                    why
+                );
+                let response = self
+                    .chat_provider
+                    .chat_complete(model, generation, conversation)
+                    .await?;
+                let content = with_sources(&response.content, passages);
+                self.sync_reply_chain(ctx, msg, &mut replies, &content)
+                    .await?;
+                return Ok(response);
+            }
+        };
 
-                    return Ok(());
+        let placeholder = msg
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.content(STREAM_PLACEHOLDER).reference_message(msg)
+            })
+            .await?;
+        replies.push(placeholder);
+        let mut content = String::new();
+        let mut interrupted = false;
+        let mut shown_len = 0;
+        let mut last_edit = Instant::now();
+        while let Some(delta) = stream.next().await {
+            match delta {
+                Ok(delta) => content.push_str(&delta),
+                Err(why) if content.trim().is_empty() => {
+                    self.delete_replies(ctx, &replies).await;
+                    return Err(why);
                 }
+                Err(why) => {
+                    warn!("Chat stream broke off: {:?}, keep the partial answer", why);
+                    interrupted = true;
+                    break;
+                }
+            }
 
-                // Get response from gpt-3.5
-                let response = self.openai_client.chat_complete(conversation).await?;
-                let response_sent = msg
-                    .channel_id
-                    .send_message(&ctx.http, |m| m.content(response).reference_message(&msg))
+            if last_edit.elapsed() >= STREAM_EDIT_INTERVAL
+                && content.len() != shown_len
+                && !content.trim().is_empty()
+            {
+                self.sync_reply_chain(ctx, msg, &mut replies, &content)
                     .await?;
+                shown_len = content.len();
+                last_edit = Instant::now();
+            }
+        }
+
+        if content.trim().is_empty() {
+            self.delete_replies(ctx, &replies).await;
+            return Err(anyhow!("Empty chat stream"));
+        }
+        let shown = match interrupted {
+            true => format!("{}\n\n{}", content.trim_end(), STREAM_INTERRUPTED),
+            false => content.clone(),
+        };
+        self.sync_reply_chain(ctx, msg, &mut replies, &with_sources(&shown, passages))
+            .await?;
+        // Streamed answers don't report their usage, it's estimated from the content received.
+        Ok(ChatResponse {
+            content,
+            usage: None,
+        })
+    }
+
+    async fn _message(&self, ctx: Context, msg: Message) -> Result<(), AnswerError> {
+        // Never answer bots, including ourselves in direct messages.
+        if msg.author.bot {
+            return Ok(());
+        }
+
+        let settings = self.settings_for(msg.guild_id, msg.channel_id);
+        if settings.trigger == TriggerMode::Slash || !settings.allows(msg.guild_id, msg.channel_id)
+        {
+            return Ok(());
+        }
 
-                // Cache conversation
-                vec![(Role::User, msg.clone()), (Role::Assistant, response_sent)]
-                    .into_iter()
-                    .for_each(|x| {
-                        self.conversation_cache
-                            .add_message(msg.author.id, x.0, &x.1.content, None)
-                            .log_error("Cache Conversation failed");
-                    });
-                Ok(())
+        // Extract question from message
+        let bot_id = ctx.cache.current_user_id();
+        let pinged =
+            settings.trigger == TriggerMode::All || msg.mentions.iter().any(|x| x.id == bot_id);
+        let direct = msg.guild_id.is_none();
+        let question = match question_for(bot_id, &msg.content, pinged, direct) {
+            Some(question) => question,
+            None => {
+                trace!("Content: {:?}", &msg.content);
+                return Ok(());
             }
+        };
+        info!(
+            "Mentioned by {:?}, Content: {:?}",
+            &msg.author.name, &msg.content
+        );
+        // Check the limits before any request to the api is made.
+        if let Err(limited) = self
+            .rate_limiter
+            .check(msg.author.id, msg.channel_id, msg.guild_id)
+        {
+            info!("Limited {:?}: {:?}", &msg.author.name, limited);
+            msg.channel_id
+                .send_message(&ctx.http, |m| {
+                    m.content(limited.reply()).reference_message(&msg)
+                })
+                .await?;
+            return Ok(());
         }
+        let typing = msg.channel_id.start_typing(&ctx.http)?;
+
+        // Build conversation of the message, and find related knowledge. Messages of shared
+        // conversations carry the name of their author.
+        let key = self
+            .conversation_key(&ctx, msg.author.id, msg.channel_id)
+            .await?;
+        let name = key.is_shared().then(|| participant_name(&msg.author.name));
+        let origin = MessageOrigin {
+            user_id: msg.author.id,
+            channel_id: msg.channel_id,
+            guild_id: msg.guild_id,
+        };
+
+        // A reply continues the conversation of the replied messages, even if it's not kept
+        // anymore.
+        let history = match msg.message_reference {
+            Some(_) => {
+                let chain = fetch_reply_chain(&ctx, &msg).await;
+                (!chain.is_empty()).then(|| conversation_from_chain(&chain, key.is_shared()))
+            }
+            None => None,
+        };
+        let (conversation, passages) = self
+            .prepare_answer(&settings, key, &question, name.clone(), history, origin)
+            .await?;
+
+        // Get response from the model of the channel
+        let prompt_tokens = self.prompt_tokens(&settings, &conversation)?;
+        let response = if self.stream_replies {
+            let _t = typing.stop();
+            self.send_streaming_reply(&ctx, &msg, &settings, conversation, &passages)
+                .await?
+        } else {
+            let response = self
+                .chat_provider
+                .chat_complete(&settings.model, &settings.generation, conversation)
+                .await?;
+            let content = with_sources(&response.content, &passages);
+            self.sync_reply_chain(&ctx, &msg, &mut Vec::new(), &content)
+                .await?;
+            response
+        };
+
+        // Cache conversation
+        self.record_chat_usage(origin, &settings.model, prompt_tokens, &response);
+        self.remember_answer(
+            key,
+            [(&question, name), (&response.content, None)],
+            passages,
+            origin,
+        );
+        self.summarize_history(&settings, key, origin)
+            .await
+            .log_error("Summarize conversation failed");
+        Ok(())
     }
 }
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::{QdrantClient, QdrantClientConfig},
+    qdrant::{
+        point_id::PointIdOptions, r#match::MatchValue, value::Kind, vectors::VectorsOptions,
+        vectors_config::Config, CollectionStatus, CountPoints, CreateCollection, FieldCondition,
+        FieldType, IsEmptyCondition, ListValue, Match, PayloadSchemaType, PointId, PointStruct,
+        RetrievedPoint, ScrollPoints, SearchPoints, Struct, Value, VectorParams, VectorsConfig,
+    },
+};
+
+use crate::vector_store::{
+    CollectionConfig, CollectionInfo, Condition, Distance, Filter, IndexType, Payload, ScoredPoint,
+    ScrollPage, VectorPoint, VectorStore,
+};
+
+/// Collections kept by a Qdrant server.
+pub struct QdrantStore {
+    pub client: QdrantClient,
+}
+
+impl QdrantStore {
+    /// A store of the Qdrant server at `url`, which is connected to on the first request.
+    pub async fn new(url: &str) -> Result<Self> {
+        let config = QdrantClientConfig::from_url(url);
+        Ok(Self {
+            client: QdrantClient::new(Some(config)).await?,
+        })
+    }
+}
+
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::DoubleValue(x)) => serde_json::Number::from_f64(x)
+            .map(serde_json::Value::Number)
+            .unwrap_or_default(),
+        Some(Kind::IntegerValue(x)) => x.into(),
+        Some(Kind::StringValue(x)) => x.into(),
+        Some(Kind::BoolValue(x)) => x.into(),
+        Some(Kind::StructValue(x)) => serde_json::Value::Object(
+            x.fields
+                .into_iter()
+                .map(|(key, value)| (key, value_to_json(value)))
+                .collect(),
+        ),
+        Some(Kind::ListValue(x)) => x.values.into_iter().map(value_to_json).collect(),
+    }
+}
+
+fn json_to_value(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(x) => Kind::BoolValue(x),
+        serde_json::Value::Number(x) => match x.as_i64() {
+            Some(x) => Kind::IntegerValue(x),
+            None => Kind::DoubleValue(x.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(x) => Kind::StringValue(x),
+        serde_json::Value::Array(x) => Kind::ListValue(ListValue {
+            values: x.into_iter().map(json_to_value).collect(),
+        }),
+        serde_json::Value::Object(x) => Kind::StructValue(Struct {
+            fields: x
+                .into_iter()
+                .map(|(key, value)| (key, json_to_value(value)))
+                .collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+fn to_payload(payload: HashMap<String, Value>) -> Payload {
+    payload
+        .into_iter()
+        .map(|(key, value)| (key, value_to_json(value)))
+        .collect()
+}
+
+fn to_point_id(id: &str) -> PointId {
+    match id.parse::<u64>() {
+        Ok(id) => id.into(),
+        Err(_) => id.to_string().into(),
+    }
+}
+
+fn from_point_id(id: Option<PointId>) -> Result<String> {
+    match id.and_then(|x| x.point_id_options) {
+        Some(PointIdOptions::Num(id)) => Ok(id.to_string()),
+        Some(PointIdOptions::Uuid(id)) => Ok(id),
+        None => Err(anyhow!("Point without id")),
+    }
+}
+
+impl TryFrom<RetrievedPoint> for VectorPoint {
+    type Error = anyhow::Error;
+
+    fn try_from(point: RetrievedPoint) -> Result<Self> {
+        let id = from_point_id(point.id)?;
+        let vector = match point.vectors.and_then(|x| x.vectors_options) {
+            Some(VectorsOptions::Vector(vector)) => vector.data,
+            Some(VectorsOptions::Vectors(_)) => {
+                return Err(anyhow!("Named vectors of point {} aren't supported", id))
+            }
+            None => vec![],
+        };
+        Ok(Self {
+            id,
+            vector,
+            payload: to_payload(point.payload),
+        })
+    }
+}
+
+impl From<VectorPoint> for PointStruct {
+    fn from(point: VectorPoint) -> Self {
+        PointStruct {
+            id: Some(to_point_id(&point.id)),
+            payload: point
+                .payload
+                .into_iter()
+                .map(|(key, value)| (key, json_to_value(value)))
+                .collect(),
+            vectors: Some(point.vector.into()),
+        }
+    }
+}
+
+impl From<&Condition> for qdrant_client::qdrant::Condition {
+    fn from(condition: &Condition) -> Self {
+        let field = |key: &str, match_value| {
+            FieldCondition {
+                key: key.into(),
+                r#match: Some(Match {
+                    match_value: Some(match_value),
+                }),
+                ..Default::default()
+            }
+            .into()
+        };
+        match condition {
+            Condition::Keyword { key, value } => field(key, MatchValue::Keyword(value.clone())),
+            Condition::Integer { key, value } => field(key, MatchValue::Integer(*value)),
+            Condition::Empty { key } => IsEmptyCondition { key: key.clone() }.into(),
+            Condition::Filter(filter) => qdrant_client::qdrant::Filter::from(filter).into(),
+        }
+    }
+}
+
+impl From<&Filter> for qdrant_client::qdrant::Filter {
+    fn from(filter: &Filter) -> Self {
+        let conditions = |conditions: &[Condition]| conditions.iter().map(Into::into).collect();
+        Self {
+            must: conditions(&filter.must),
+            should: conditions(&filter.should),
+            must_not: conditions(&filter.must_not),
+        }
+    }
+}
+
+impl Distance {
+    fn to_qdrant(self) -> qdrant_client::qdrant::Distance {
+        match self {
+            Distance::Cosine => qdrant_client::qdrant::Distance::Cosine,
+            Distance::Euclid => qdrant_client::qdrant::Distance::Euclid,
+            Distance::Dot => qdrant_client::qdrant::Distance::Dot,
+        }
+    }
+
+    fn from_qdrant(distance: i32) -> Option<Self> {
+        match qdrant_client::qdrant::Distance::from_i32(distance)? {
+            qdrant_client::qdrant::Distance::Cosine => Some(Distance::Cosine),
+            qdrant_client::qdrant::Distance::Euclid => Some(Distance::Euclid),
+            qdrant_client::qdrant::Distance::Dot => Some(Distance::Dot),
+            qdrant_client::qdrant::Distance::UnknownDistance => None,
+        }
+    }
+}
+
+impl IndexType {
+    fn field_type(self) -> FieldType {
+        match self {
+            IndexType::Keyword => FieldType::Keyword,
+            IndexType::Integer => FieldType::Integer,
+            IndexType::Float => FieldType::Float,
+            IndexType::Geo => FieldType::Geo,
+            IndexType::Text => FieldType::Text,
+        }
+    }
+
+    fn from_schema_type(schema_type: i32) -> Option<Self> {
+        match PayloadSchemaType::from_i32(schema_type)? {
+            PayloadSchemaType::Keyword => Some(IndexType::Keyword),
+            PayloadSchemaType::Integer => Some(IndexType::Integer),
+            PayloadSchemaType::Float => Some(IndexType::Float),
+            PayloadSchemaType::Geo => Some(IndexType::Geo),
+            PayloadSchemaType::Text => Some(IndexType::Text),
+            PayloadSchemaType::UnknownType => None,
+        }
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantStore {
+    async fn health_check(&self) -> Result<()> {
+        self.client.health_check().await?;
+        Ok(())
+    }
+
+    async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>> {
+        if !self.client.has_collection(collection).await? {
+            return Ok(None);
+        }
+        let info = self
+            .client
+            .collection_info(collection)
+            .await?
+            .result
+            .ok_or_else(|| anyhow!("No collection {}", collection))?;
+        let params = match info
+            .config
+            .and_then(|x| x.params)
+            .and_then(|x| x.vectors_config)
+            .and_then(|x| x.config)
+        {
+            Some(Config::Params(params)) => params,
+            _ => {
+                return Err(anyhow!(
+                    "Only collections with one unnamed vector are supported"
+                ))
+            }
+        };
+        let status = CollectionStatus::from_i32(info.status)
+            .unwrap_or(CollectionStatus::UnknownCollectionStatus);
+        Ok(Some(CollectionInfo {
+            config: CollectionConfig {
+                vector_size: params.size,
+                distance: Distance::from_qdrant(params.distance)
+                    .ok_or_else(|| anyhow!("Unknown distance {}", params.distance))?,
+                indexes: info
+                    .payload_schema
+                    .into_iter()
+                    .filter_map(|(field, schema)| {
+                        Some((field, IndexType::from_schema_type(schema.data_type)?))
+                    })
+                    .collect(),
+            },
+            points: info.points_count,
+            status: format!("{:?}", status),
+        }))
+    }
+
+    async fn create_collection(&self, collection: &str, config: &CollectionConfig) -> Result<()> {
+        self.client
+            .create_collection(&CreateCollection {
+                collection_name: collection.into(),
+                vectors_config: Some(VectorsConfig {
+                    config: Some(Config::Params(VectorParams {
+                        size: config.vector_size,
+                        distance: config.distance.to_qdrant().into(),
+                    })),
+                }),
+                ..Default::default()
+            })
+            .await?;
+        for (field, index_type) in config.indexes.iter() {
+            self.client
+                .create_field_index_blocking(collection, field, index_type.field_type(), None, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_collection(&self, collection: &str) -> Result<()> {
+        self.client.delete_collection(collection).await?;
+        Ok(())
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<VectorPoint>) -> Result<()> {
+        let points = points.into_iter().map(PointStruct::from).collect();
+        self.client
+            .upsert_points_blocking(collection, points, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<&Filter>,
+        limit: usize,
+        score_threshold: Option<f32>,
+    ) -> Result<Vec<ScoredPoint>> {
+        let response = self
+            .client
+            .search_points(&SearchPoints {
+                collection_name: collection.into(),
+                vector: vector.to_vec(),
+                filter: filter.map(Into::into),
+                limit: limit as u64,
+                with_payload: Some(true.into()),
+                score_threshold,
+                ..Default::default()
+            })
+            .await?;
+        response
+            .result
+            .into_iter()
+            .map(|x| {
+                Ok(ScoredPoint {
+                    id: from_point_id(x.id)?,
+                    score: x.score,
+                    payload: to_payload(x.payload),
+                })
+            })
+            .collect()
+    }
+
+    async fn delete(&self, collection: &str, filter: &Filter) -> Result<()> {
+        let filter = qdrant_client::qdrant::Filter::from(filter);
+        self.client
+            .delete_points_blocking(collection, &filter.into(), None)
+            .await?;
+        Ok(())
+    }
+
+    async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64> {
+        let count = self
+            .client
+            .count(&CountPoints {
+                collection_name: collection.into(),
+                filter: filter.map(Into::into),
+                exact: Some(true),
+            })
+            .await?
+            .result
+            .ok_or_else(|| anyhow!("No result"))?
+            .count;
+        Ok(count)
+    }
+
+    async fn scroll(
+        &self,
+        collection: &str,
+        filter: Option<&Filter>,
+        offset: Option<&str>,
+        limit: usize,
+        with_vectors: bool,
+    ) -> Result<ScrollPage> {
+        let response = self
+            .client
+            .scroll(&ScrollPoints {
+                collection_name: collection.into(),
+                filter: filter.map(Into::into),
+                offset: offset.map(to_point_id),
+                limit: Some(limit as u32),
+                with_payload: Some(true.into()),
+                with_vectors: Some(with_vectors.into()),
+                ..Default::default()
+            })
+            .await?;
+        let points = response
+            .result
+            .into_iter()
+            .map(VectorPoint::try_from)
+            .collect::<Result<_>>()?;
+        let next = match response.next_page_offset {
+            Some(id) => Some(from_point_id(Some(id))?),
+            None => None,
+        };
+        Ok((points, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use qdrant_client::qdrant::{PointStruct, RetrievedPoint, Value};
+
+    use super::to_point_id;
+    use crate::vector_store::VectorPoint;
+
+    #[test]
+    fn test_point_round_trip() {
+        let payload: HashMap<String, Value> = HashMap::from([
+            ("doc_id".to_string(), "https://a".into()),
+            ("chunk_index".to_string(), 3i64.into()),
+            ("score".to_string(), 0.5f64.into()),
+            ("tags".to_string(), vec!["faq", "setup"].into()),
+        ]);
+        let point = RetrievedPoint {
+            id: Some("5c56c793-69f3-4fbf-87e6-c4bf54c28c26".to_string().into()),
+            payload: payload.clone(),
+            vectors: Some(vec![0.25, -1.0].into()),
+        };
+
+        let point = VectorPoint::try_from(point).unwrap();
+        assert_eq!(point.id, "5c56c793-69f3-4fbf-87e6-c4bf54c28c26");
+        assert_eq!(point.payload["chunk_index"], 3);
+        let point: PointStruct = point.into();
+        assert_eq!(point.payload, payload);
+        assert_eq!(point.vectors, Some(vec![0.25, -1.0].into()));
+
+        assert_eq!(to_point_id("7"), 7.into());
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log_error::LogError;
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::{ChannelId, GuildId, UserId};
+use tracing::trace;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How often the bot may be asked, and how many tokens it may spend a day. A limit of 0 means no
+/// limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitConfig {
+    /// Questions a user may ask per minute.
+    pub user_per_minute: u32,
+    /// Questions which may be asked in a channel per minute.
+    pub channel_per_minute: u32,
+    /// Questions which may be asked in a guild per minute.
+    pub guild_per_minute: u32,
+    /// Tokens the questions of a user may consume per day.
+    pub user_daily_tokens: u64,
+    /// Tokens the questions of a guild may consume per day.
+    pub guild_daily_tokens: u64,
+}
+
+/// Why a question isn't answered now, and when it can be asked again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Rate(SystemTime),
+    UserQuota(SystemTime),
+    GuildQuota(SystemTime),
+}
+
+impl Limited {
+    pub fn retry_at(&self) -> SystemTime {
+        match self {
+            Limited::Rate(at) | Limited::UserQuota(at) | Limited::GuildQuota(at) => *at,
+        }
+    }
+
+    /// A reply telling when the question can be asked again, rendered by discord relative to
+    /// the time of the reader.
+    pub fn reply(&self) -> String {
+        let retry_at = self
+            .retry_at()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            .ceil();
+        let reason = match self {
+            Limited::Rate(_) => "I'm getting more questions than I can answer right now.",
+            Limited::UserQuota(_) => "You have used up your questions for today.",
+            Limited::GuildQuota(_) => "This server has used up its questions for today.",
+        };
+        format!("{} Please try again <t:{}:R>.", reason, retry_at)
+    }
+}
+
+/// Requests which may be made right away, refilled continuously up to the limit per minute.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct TokenBucket {
+    tokens: f64,
+    /// Seconds since the unix epoch of the last refill.
+    updated: f64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, per_minute: u32, now: f64) {
+        let capacity = f64::from(per_minute);
+        let refilled = (now - self.updated).max(0.0) * capacity / 60.0;
+        self.tokens = (self.tokens + refilled).min(capacity);
+        self.updated = now;
+    }
+
+    /// Seconds until a request may be made.
+    fn wait(&self, per_minute: u32) -> f64 {
+        (1.0 - self.tokens).max(0.0) * 60.0 / f64::from(per_minute)
+    }
+}
+
+/// Tokens consumed on a day, counted in days since the unix epoch.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct DailyUsage {
+    day: u64,
+    tokens: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counters {
+    buckets: HashMap<String, TokenBucket>,
+    usage: HashMap<String, DailyUsage>,
+    /// Raised whenever the counters are saved, so an older save never overwrites a newer one.
+    #[serde(skip)]
+    version: u64,
+}
+
+impl Counters {
+    /// Drop the buckets which are full again and the usage of past days.
+    fn prune(&mut self, now: f64) {
+        self.buckets.retain(|_, x| now - x.updated < 60.0);
+        let today = now as u64 / SECONDS_PER_DAY;
+        self.usage.retain(|_, x| x.day == today);
+    }
+}
+
+/// Counters to be written into the file of a rate limiter.
+struct CountersSnapshot {
+    path: PathBuf,
+    version: u64,
+    content: String,
+    /// Version of the counters in the file, locked while writing it.
+    written: Arc<Mutex<u64>>,
+}
+
+impl CountersSnapshot {
+    /// Write the counters, unless newer ones have been written already.
+    fn write(self) -> Result<()> {
+        let mut written = match self.written.lock() {
+            Ok(written) => written,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if *written >= self.version {
+            return Ok(());
+        }
+        // Write aside and rename, so a crash never leaves half of the counters behind.
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        fs::write(&temp, self.content)
+            .with_context(|| format!("Writing rate limits {:?}", temp))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("Writing rate limits {:?}", self.path))?;
+        *written = self.version;
+        Ok(())
+    }
+}
+
+/// Token buckets per user, channel and guild, and daily token quotas per user and guild. The
+/// counters are kept in a JSON file, if any, to survive restarts.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    path: Option<PathBuf>,
+    counters: Mutex<Counters>,
+    written: Arc<Mutex<u64>>,
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn user_key(user_id: UserId) -> String {
+    format!("user:{}", user_id)
+}
+
+fn channel_key(channel_id: ChannelId) -> String {
+    format!("channel:{}", channel_id)
+}
+
+fn guild_key(guild_id: GuildId) -> String {
+    format!("guild:{}", guild_id)
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            path: None,
+            counters: Mutex::new(Counters::default()),
+            written: Arc::default(),
+        }
+    }
+
+    /// A rate limiter keeping its counters in `path`, starting from the counters in it if it
+    /// exists.
+    pub fn open(config: RateLimitConfig, path: &Path) -> Result<Self> {
+        let counters = match path.exists() {
+            true => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Reading rate limits {:?}", path))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Parsing rate limits {:?}", path))?
+            }
+            false => Counters::default(),
+        };
+        Ok(Self {
+            config,
+            path: Some(path.to_path_buf()),
+            counters: Mutex::new(counters),
+            written: Arc::default(),
+        })
+    }
+
+    /// Take a request from the buckets of the user, the channel and the guild, unless one of
+    /// them is empty or a daily quota is used up.
+    pub fn check(
+        &self,
+        user_id: UserId,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+    ) -> Result<(), Limited> {
+        self.check_at(user_id, channel_id, guild_id, SystemTime::now())
+    }
+
+    fn check_at(
+        &self,
+        user_id: UserId,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        now: SystemTime,
+    ) -> Result<(), Limited> {
+        let mut counters = match self.counters.lock() {
+            Ok(counters) => counters,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now_secs = seconds(now);
+        counters.prune(now_secs);
+        let today = now_secs as u64 / SECONDS_PER_DAY;
+        let tomorrow = UNIX_EPOCH + Duration::from_secs((today + 1) * SECONDS_PER_DAY);
+
+        let mut quotas = vec![(
+            user_key(user_id),
+            self.config.user_daily_tokens,
+            Limited::UserQuota(tomorrow),
+        )];
+        if let Some(guild_id) = guild_id {
+            quotas.push((
+                guild_key(guild_id),
+                self.config.guild_daily_tokens,
+                Limited::GuildQuota(tomorrow),
+            ));
+        }
+        for (key, quota, limited) in quotas {
+            let used = counters
+                .usage
+                .get(&key)
+                .filter(|x| x.day == today)
+                .map_or(0, |x| x.tokens);
+            if quota > 0 && used >= quota {
+                trace!("Daily quota of {} used up: {}/{}", key, used, quota);
+                return Err(limited);
+            }
+        }
+
+        let mut limits = vec![
+            (user_key(user_id), self.config.user_per_minute),
+            (channel_key(channel_id), self.config.channel_per_minute),
+        ];
+        if let Some(guild_id) = guild_id {
+            limits.push((guild_key(guild_id), self.config.guild_per_minute));
+        }
+        limits.retain(|x| x.1 > 0);
+
+        let mut wait: f64 = 0.0;
+        for (key, per_minute) in limits.iter() {
+            let bucket = counters.buckets.entry(key.clone()).or_insert(TokenBucket {
+                tokens: f64::from(*per_minute),
+                updated: now_secs,
+            });
+            bucket.refill(*per_minute, now_secs);
+            wait = wait.max(bucket.wait(*per_minute));
+        }
+        if wait > 0.0 {
+            trace!("Rate limited for {:.1}s", wait);
+            return Err(Limited::Rate(now + Duration::from_secs_f64(wait)));
+        }
+        for (key, _) in limits.iter() {
+            if let Some(bucket) = counters.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count `tokens` consumed by a question of the user into the daily quotas, and save the
+    /// counters in the background.
+    pub fn record_usage(
+        &self,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        tokens: u64,
+    ) -> Result<()> {
+        if let Some(snapshot) =
+            self.record_usage_at(user_id, guild_id, tokens, SystemTime::now())?
+        {
+            tokio::task::spawn_blocking(move || {
+                snapshot.write().log_error("Saving rate limits failed")
+            });
+        }
+        Ok(())
+    }
+
+    /// Count `tokens` into the daily quotas, and return the counters to save if they are kept
+    /// in a file.
+    fn record_usage_at(
+        &self,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        tokens: u64,
+        now: SystemTime,
+    ) -> Result<Option<CountersSnapshot>> {
+        let mut counters = match self.counters.lock() {
+            Ok(counters) => counters,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now_secs = seconds(now);
+        counters.prune(now_secs);
+        let today = now_secs as u64 / SECONDS_PER_DAY;
+        let keys = std::iter::once(user_key(user_id)).chain(guild_id.map(guild_key));
+        for key in keys {
+            let usage = counters.usage.entry(key).or_insert(DailyUsage {
+                day: today,
+                tokens: 0,
+            });
+            if usage.day != today {
+                *usage = DailyUsage {
+                    day: today,
+                    tokens: 0,
+                };
+            }
+            usage.tokens += tokens;
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        counters.version += 1;
+        Ok(Some(CountersSnapshot {
+            path: path.clone(),
+            version: counters.version,
+            content: serde_json::to_string(&*counters)?,
+            written: self.written.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serenity::model::prelude::{ChannelId, GuildId, UserId};
+
+    use super::{Limited, RateLimitConfig, RateLimiter};
+
+    #[test]
+    fn test_rate_limits_and_quotas() {
+        let config = RateLimitConfig {
+            user_per_minute: 2,
+            channel_per_minute: 3,
+            guild_per_minute: 0,
+            user_daily_tokens: 100,
+            guild_daily_tokens: 0,
+        };
+        let path = std::env::temp_dir().join(format!("{}.json", uuid::Uuid::new_v4()));
+        let limiter = RateLimiter::open(config, &path).unwrap();
+        let (alice, bob) = (UserId(1), UserId(2));
+        let (channel, guild) = (ChannelId(10), Some(GuildId(100)));
+        let start = UNIX_EPOCH + Duration::from_secs(1_000 * 86_400);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert!(limiter.check_at(alice, channel, guild, at(0)).is_ok());
+        assert!(limiter.check_at(alice, channel, guild, at(0)).is_ok());
+        assert_eq!(
+            limiter.check_at(alice, channel, guild, at(0)),
+            Err(Limited::Rate(at(30)))
+        );
+        assert!(limiter.check_at(bob, channel, guild, at(0)).is_ok());
+        // The channel is exhausted for everyone.
+        assert_eq!(
+            limiter.check_at(bob, channel, guild, at(0)),
+            Err(Limited::Rate(at(20)))
+        );
+        assert!(limiter.check_at(alice, channel, guild, at(30)).is_ok());
+
+        let snapshot = limiter.record_usage_at(alice, guild, 100, at(30)).unwrap();
+        snapshot.unwrap().write().unwrap();
+        // The quota survives a restart, and is reset the next day.
+        let limiter = RateLimiter::open(config, &path).unwrap();
+        assert_eq!(
+            limiter.check_at(alice, channel, guild, at(120)),
+            Err(Limited::UserQuota(at(86_400)))
+        );
+        assert!(limiter.check_at(bob, channel, guild, at(120)).is_ok());
+        assert!(limiter.check_at(alice, channel, guild, at(86_400)).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_prune_without_file() {
+        let config = RateLimitConfig {
+            user_per_minute: 2,
+            channel_per_minute: 3,
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(config);
+        let channel = ChannelId(10);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000 * 86_400);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        for user in 1..=3 {
+            assert!(limiter.check_at(UserId(user), channel, None, at(0)).is_ok());
+            let snapshot = limiter.record_usage_at(UserId(user), None, 10, at(0));
+            assert!(snapshot.unwrap().is_none());
+        }
+        assert!(limiter
+            .check_at(UserId(4), ChannelId(11), None, at(86_400))
+            .is_ok());
+        let counters = limiter.counters.lock().unwrap();
+        assert_eq!(counters.buckets.len(), 2);
+        assert!(counters.usage.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use async_openai::types::Role;
+use serenity::{model::channel::Message, prelude::*};
+use tracing::{trace, warn};
+
+use crate::{
+    conversation::{participant_name, ConversationCtx},
+    retrieval::strip_sources,
+    trigger::strip_mentions,
+};
+
+/// Maximal number of messages followed up a chain of replies.
+const MAX_REPLY_CHAIN_LENGTH: usize = 20;
+
+/// A message of a chain of replies, reduced to what the conversation needs.
+#[derive(Debug, Clone)]
+pub struct ChainMessage {
+    pub from_bot: bool,
+    pub author: String,
+    pub content: String,
+}
+
+/// The message `msg` replies to, from the message itself, the cache, or discord.
+async fn referenced_message(ctx: &Context, msg: &Message) -> Option<Message> {
+    if let Some(referenced) = &msg.referenced_message {
+        return Some(*referenced.clone());
+    }
+    let reference = msg.message_reference.as_ref()?;
+    let message_id = reference.message_id?;
+    if let Some(cached) = ctx.cache.message(reference.channel_id, message_id) {
+        return Some(cached);
+    }
+    match reference.channel_id.message(&ctx.http, message_id).await {
+        Ok(fetched) => Some(fetched),
+        Err(why) => {
+            warn!("Fetching replied message {} failed: {:?}", message_id, why);
+            None
+        }
+    }
+}
+
+/// The messages `msg` replies to, directly or through other replies, oldest first.
+pub async fn fetch_reply_chain(ctx: &Context, msg: &Message) -> Vec<ChainMessage> {
+    let bot_id = ctx.cache.current_user_id();
+    let mut chain = Vec::new();
+    let mut current = msg.clone();
+    while chain.len() < MAX_REPLY_CHAIN_LENGTH {
+        current = match referenced_message(ctx, &current).await {
+            Some(referenced) => referenced,
+            None => break,
+        };
+        let from_bot = current.author.id == bot_id;
+        let content = match from_bot {
+            true => current.content.clone(),
+            false => strip_mentions(bot_id, &current.content).0,
+        };
+        chain.push(ChainMessage {
+            from_bot,
+            author: current.author.name.clone(),
+            content,
+        });
+    }
+    trace!("Reply chain of {} messages", chain.len());
+    chain.reverse();
+    chain
+}
+
+/// Rebuild a conversation from a chain of replies, oldest first. The parts an answer was split
+/// into are joined again, and the sources listed under answers are left out. Messages of
+/// `shared` conversations carry the name of their author.
+pub fn conversation_from_chain(chain: &[ChainMessage], shared: bool) -> ConversationCtx {
+    let mut conversation = ConversationCtx::default();
+    let mut answer = String::new();
+    for message in chain.iter() {
+        if message.from_bot {
+            let part = strip_sources(&message.content).trim();
+            if !answer.is_empty() {
+                answer.push('\n');
+            }
+            answer.push_str(part);
+            continue;
+        }
+        if !answer.trim().is_empty() {
+            conversation.add_message(Role::Assistant, &answer, None);
+        }
+        answer.clear();
+
+        let content = message.content.trim();
+        if !content.is_empty() {
+            let name = shared.then(|| participant_name(&message.author));
+            conversation.add_message(Role::User, content, name);
+        }
+    }
+    if !answer.trim().is_empty() {
+        conversation.add_message(Role::Assistant, &answer, None);
+    }
+    conversation
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+
+    use super::{conversation_from_chain, ChainMessage};
+
+    fn message(from_bot: bool, content: &str) -> ChainMessage {
+        ChainMessage {
+            from_bot,
+            author: match from_bot {
+                true => "bot".into(),
+                false => "alice".into(),
+            },
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn test_conversation_from_chain() {
+        let chain = vec![
+            message(false, "What is it?"),
+            message(true, "It is a bot."),
+            message(true, "It answers [1].\n\n**Sources**\n[1] Readme"),
+            message(false, "How do I start it?"),
+        ];
+        let conversation = conversation_from_chain(&chain, true);
+
+        assert_eq!(conversation.len(), 3);
+        assert!(matches!(conversation[0].role, Role::User));
+        assert_eq!(conversation[0].name.as_deref(), Some("alice"));
+        assert!(matches!(conversation[1].role, Role::Assistant));
+        assert_eq!(conversation[1].content, "It is a bot.\nIt answers [1].");
+        assert_eq!(conversation[2].content, "How do I start it?");
+    }
+}
@@ -0,0 +1,255 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+use crate::{ai::TokenEncoder, conversation::ConversationKey, knowledge_base::KnowledgePayload};
+
+/// Heading of the sources listed under an answer.
+const SOURCES_HEADING: &str = "**Sources**";
+
+/// How much knowledge is retrieved for a question, and how much of it is given to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrievalConfig {
+    /// Maximal number of passages retrieved for a question.
+    pub top_k: usize,
+    /// Maximal number of tokens of all passages given to the model.
+    pub token_budget: usize,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self {
+            top_k: 5,
+            token_budget: 1500,
+        }
+    }
+}
+
+/// A piece of knowledge given to the model, numbered so the answer can refer to it.
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub number: usize,
+    pub title: String,
+    pub url: String,
+    pub content: String,
+}
+
+impl Passage {
+    pub fn render(&self) -> String {
+        match self.url.is_empty() {
+            true => format!("[{}] {}\n{}", self.number, self.title, self.content),
+            false => format!(
+                "[{}] {} ({})\n{}",
+                self.number, self.title, self.url, self.content
+            ),
+        }
+    }
+}
+
+/// The passages given with the last question of each conversation.
+#[derive(Debug)]
+pub struct PassageCache(Mutex<LruCache<ConversationKey, Vec<Passage>>>);
+
+impl Default for PassageCache {
+    fn default() -> Self {
+        Self(Mutex::new(LruCache::new(
+            NonZeroUsize::new(256).expect("Unreachable!"),
+        )))
+    }
+}
+
+impl PassageCache {
+    pub fn put(&self, key: ConversationKey, passages: Vec<Passage>) {
+        if let Ok(mut map) = self.0.lock() {
+            map.put(key, passages);
+        }
+    }
+
+    pub fn get(&self, key: ConversationKey) -> Vec<Passage> {
+        match self.0.lock() {
+            Ok(mut map) => map.get(&key).cloned().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn clear(&self, key: ConversationKey) {
+        if let Ok(mut map) = self.0.lock() {
+            map.pop(&key);
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Drop documents, ordered by relevance, which repeat a more relevant one: the same document, or
+/// content which is already contained in a kept document.
+pub fn dedup_documents(documents: Vec<KnowledgePayload>) -> Vec<KnowledgePayload> {
+    let mut kept: Vec<(String, String, KnowledgePayload)> = Vec::new();
+    for document in documents {
+        let doc_id = document.document_id();
+        let content = normalize(&document.content);
+        let repeated = kept
+            .iter()
+            .any(|(id, kept_content, _)| id == &doc_id || kept_content.contains(&content));
+        if !repeated && !content.is_empty() {
+            kept.push((doc_id, content, document));
+        }
+    }
+    kept.into_iter().map(|x| x.2).collect()
+}
+
+/// Number the documents, ordered by relevance, and keep as many as fit into `token_budget`.
+/// A document which doesn't fit is left out, a less relevant and shorter one may still fit.
+pub fn pack_passages(
+    encoder: &TokenEncoder,
+    documents: Vec<KnowledgePayload>,
+    token_budget: usize,
+) -> Vec<Passage> {
+    let mut passages = Vec::new();
+    let mut tokens = 0;
+    for document in dedup_documents(documents) {
+        let passage = Passage {
+            number: passages.len() + 1,
+            title: document.title,
+            url: document.url,
+            content: document.content,
+        };
+        // One more for the separator to the next passage.
+        let passage_tokens = encoder
+            .0
+            .encode_with_special_tokens(&passage.render())
+            .len()
+            + 1;
+        if tokens + passage_tokens > token_budget {
+            continue;
+        }
+        tokens += passage_tokens;
+        passages.push(passage);
+    }
+    passages
+}
+
+/// The knowledge part of a question.
+pub fn format_passages(passages: &[Passage]) -> String {
+    passages
+        .iter()
+        .map(|x| x.render())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Numbers of the passages an answer cites, like `[1]`, `[2, 3]` or `[1][4]`, in order of their
+/// first citation.
+pub fn cited_numbers(answer: &str) -> Vec<usize> {
+    let mut numbers = Vec::new();
+    for (start, _) in answer.match_indices('[') {
+        let rest = &answer[start + 1..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        let citation: Option<Vec<usize>> = rest[..end]
+            .split(',')
+            .map(|x| x.trim().parse().ok())
+            .collect();
+        for number in citation.unwrap_or_default() {
+            if !numbers.contains(&number) {
+                numbers.push(number);
+            }
+        }
+    }
+    numbers
+}
+
+/// `content` without the sources section added by `with_sources`.
+pub fn strip_sources(content: &str) -> &str {
+    match content.rfind(&format!("\n\n{}\n", SOURCES_HEADING)) {
+        Some(index) => &content[..index],
+        None => content,
+    }
+}
+
+/// A "Sources" section listing the passages cited by `answer`, if it cites any.
+pub fn format_sources(answer: &str, passages: &[Passage]) -> Option<String> {
+    let lines: Vec<String> = cited_numbers(answer)
+        .into_iter()
+        .filter_map(|number| passages.iter().find(|x| x.number == number))
+        .map(|x| match x.url.is_empty() {
+            true => format!("[{}] {}", x.number, x.title),
+            // Angle brackets keep discord from embedding a preview of every source.
+            false => format!("[{}] [{}](<{}>)", x.number, x.title, x.url),
+        })
+        .collect();
+    match lines.is_empty() {
+        true => None,
+        false => Some(format!("{}\n{}", SOURCES_HEADING, lines.join("\n"))),
+    }
+}
+
+/// `answer` followed by the sources it cites.
+pub fn with_sources(answer: &str, passages: &[Passage]) -> String {
+    match format_sources(answer, passages) {
+        Some(sources) => format!("{}\n\n{}", answer.trim_end(), sources),
+        None => answer.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cited_numbers, format_passages, pack_passages, with_sources, Passage};
+    use crate::{ai::TokenEncoder, knowledge_base::KnowledgePayload};
+
+    fn document(url: &str, content: &str) -> KnowledgePayload {
+        KnowledgePayload {
+            id: None,
+            url: url.into(),
+            title: url.to_uppercase(),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn test_pack_passages() {
+        let encoder = TokenEncoder::new().unwrap();
+        let documents = vec![
+            document("a", "The bot answers   questions."),
+            document("b", "The bot answers questions."),
+            document("a", "Another chunk of a."),
+            document("c", &"Long text. ".repeat(100)),
+            document("d", "Short."),
+        ];
+        let passages = pack_passages(&encoder, documents, 40);
+
+        let urls: Vec<&str> = passages.iter().map(|x| x.url.as_str()).collect();
+        assert_eq!(urls, vec!["a", "d"]);
+        assert_eq!(
+            format_passages(&passages),
+            "[1] A (a)\nThe bot answers   questions.\n\n[2] D (d)\nShort."
+        );
+    }
+
+    #[test]
+    fn test_only_cited_sources_are_listed() {
+        assert_eq!(
+            cited_numbers("It does [2]. Also [1, 2][3] but not [x]."),
+            vec![2, 1, 3]
+        );
+
+        let passages: Vec<Passage> = ["https://a", ""]
+            .iter()
+            .enumerate()
+            .map(|(i, url)| Passage {
+                number: i + 1,
+                title: format!("Title {}", i + 1),
+                url: url.to_string(),
+                content: String::new(),
+            })
+            .collect();
+        assert_eq!(
+            with_sources("Yes [2][1] [7].", &passages),
+            "Yes [2][1] [7].\n\n**Sources**\n[2] Title 2\n[1] [Title 1](<https://a>)"
+        );
+        assert_eq!(with_sources("No citation.", &passages), "No citation.");
+    }
+}
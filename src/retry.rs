@@ -0,0 +1,487 @@
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use async_openai::error::OpenAIError;
+use async_trait::async_trait;
+use backoff::{backoff::Backoff, ExponentialBackoff, ExponentialBackoffBuilder};
+use reqwest::StatusCode;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    ai::{ChatProvider, ChatResponse, ChatStream, EmbeddingProvider},
+    conversation::ConversationCtx,
+    models::GenerationConfig,
+};
+
+/// A request to the api which failed for good, either for a reason retrying doesn't fix, or
+/// because the api stayed unavailable while it was retried.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("Api key rejected: {0}")]
+    Unauthorized(String),
+    #[error("Quota of the api key used up: {0}")]
+    QuotaExceeded(String),
+    #[error("Context length of the model exceeded: {0}")]
+    ContextLengthExceeded(String),
+    #[error("Request rejected: {0}")]
+    InvalidRequest(String),
+    #[error("Api unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl RequestError {
+    /// What the user who asked is told about the failure.
+    pub fn reply(&self) -> &'static str {
+        match self {
+            RequestError::Unauthorized(_) | RequestError::QuotaExceeded(_) => {
+                "I can't answer right now because my AI service isn't set up correctly. Please let the admins of this bot know."
+            }
+            RequestError::ContextLengthExceeded(_) => {
+                "Our conversation got too long for me. Please ask a shorter question, or start over with /reset."
+            }
+            RequestError::InvalidRequest(_) => {
+                "I couldn't process this question. Please let the admins of this bot know if it keeps happening."
+            }
+            RequestError::Unavailable(_) => {
+                "My AI service isn't responding right now. Please try again in a few minutes."
+            }
+        }
+    }
+}
+
+/// A failed request to the api, with the status of its response and the time it asked to wait
+/// before retrying in the `Retry-After` header.
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct ApiFailure {
+    pub status: StatusCode,
+    pub error: OpenAIError,
+    pub retry_after: Option<Duration>,
+}
+
+/// The time to wait asked for by a `Retry-After` header, given in seconds or as a date.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let at = httpdate::parse_http_date(value).ok()?;
+            Some(at.duration_since(now).unwrap_or_default())
+        }
+    }
+}
+
+/// Whether a failed request is worth retrying.
+#[derive(Debug, PartialEq, Eq)]
+enum Failure {
+    /// Retry, after the given time if the api asked for it.
+    Retryable(Option<Duration>),
+    Fatal(RequestError),
+}
+
+/// The time to wait before retrying, as the error message of the api repeats it like "Please try
+/// again in 20s." or "Please retry after 2 seconds." Only used without a `Retry-After` header,
+/// like for the embedding requests, whose client drops the headers.
+fn retry_after_hint(message: &str) -> Option<Duration> {
+    let message = message.to_lowercase();
+    let start = ["try again in ", "retry after "]
+        .iter()
+        .find_map(|x| message.find(x).map(|i| i + x.len()))?;
+    let rest = &message[start..];
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(rest.len());
+    let value: f64 = rest[..end].trim_end_matches('.').parse().ok()?;
+    let seconds = match rest[end..].trim_start() {
+        unit if unit.starts_with("ms") => value / 1000.0,
+        unit if unit.starts_with("min") => value * 60.0,
+        _ => value,
+    };
+    Some(Duration::from_secs_f64(seconds))
+}
+
+fn classify(error: &OpenAIError) -> Failure {
+    match error {
+        OpenAIError::ApiError(error) => {
+            let code = error
+                .code
+                .as_ref()
+                .and_then(|x| x.as_str())
+                .unwrap_or_default();
+            let message = error.message.clone();
+            let rejected = ["invalid_request_error", "authentication_error"]
+                .contains(&error.r#type.as_str())
+                && message.contains("API key");
+            if code == "invalid_api_key" || rejected {
+                Failure::Fatal(RequestError::Unauthorized(message))
+            } else if code == "insufficient_quota" || error.r#type == "insufficient_quota" {
+                Failure::Fatal(RequestError::QuotaExceeded(message))
+            } else if code == "context_length_exceeded" || message.contains("context length") {
+                Failure::Fatal(RequestError::ContextLengthExceeded(message))
+            } else if error.r#type == "invalid_request_error" {
+                Failure::Fatal(RequestError::InvalidRequest(message))
+            } else {
+                // Rate limits, overloaded or failing servers.
+                Failure::Retryable(retry_after_hint(&message))
+            }
+        }
+        OpenAIError::Reqwest(error) => match error.status() {
+            Some(status) if status.as_u16() == 401 || status.as_u16() == 403 => {
+                Failure::Fatal(RequestError::Unauthorized(error.to_string()))
+            }
+            Some(status) if status.is_client_error() && status.as_u16() != 429 => {
+                Failure::Fatal(RequestError::InvalidRequest(error.to_string()))
+            }
+            // Timeouts, broken connections, rate limits and server errors.
+            _ => Failure::Retryable(None),
+        },
+        // A garbled answer or a broken stream.
+        OpenAIError::JSONDeserialize(_) | OpenAIError::StreamError(_) => Failure::Retryable(None),
+        OpenAIError::FileSaveError(_)
+        | OpenAIError::FileReadError(_)
+        | OpenAIError::InvalidArgument(_) => {
+            Failure::Fatal(RequestError::InvalidRequest(error.to_string()))
+        }
+    }
+}
+
+/// Classify a `failure` by its status before its body: client errors other than timeouts and
+/// rate limits aren't retried, whatever their body says.
+fn classify_failure(failure: &ApiFailure) -> Failure {
+    let status = failure.status;
+    match classify(&failure.error) {
+        Failure::Fatal(error) => Failure::Fatal(error),
+        Failure::Retryable(_)
+            if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+        {
+            Failure::Fatal(RequestError::Unauthorized(failure.error.to_string()))
+        }
+        Failure::Retryable(_)
+            if status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS =>
+        {
+            Failure::Fatal(RequestError::InvalidRequest(failure.error.to_string()))
+        }
+        Failure::Retryable(hint) => Failure::Retryable(failure.retry_after.or(hint)),
+    }
+}
+
+/// How long failed requests to the api are retried, waiting exponentially longer with jitter
+/// between the attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_interval: Duration,
+    pub max_interval: Duration,
+    /// Time after which a request isn't retried anymore, zero to never retry.
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_interval: Duration::from_millis(500),
+            max_interval: Duration::from_secs(20),
+            max_elapsed: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.initial_interval)
+            .with_max_interval(self.max_interval)
+            .with_max_elapsed_time(Some(self.max_elapsed))
+            .build()
+    }
+
+    /// Run `operation` until it succeeds, fails for a reason retrying doesn't fix, or the time
+    /// to retry is up. Failures of the api end as a `RequestError`, other errors are returned
+    /// untouched.
+    pub async fn retry<T, F, Fut>(&self, what: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.backoff();
+        loop {
+            let why = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(why) => why,
+            };
+            let (error, failure) = match why.downcast_ref::<ApiFailure>() {
+                Some(failure) => (&failure.error, classify_failure(failure)),
+                None => match why.downcast_ref::<OpenAIError>() {
+                    Some(error) => (error, classify(error)),
+                    None => return Err(why),
+                },
+            };
+            let retry_after = match failure {
+                Failure::Fatal(error) => return Err(error.into()),
+                Failure::Retryable(retry_after) => retry_after,
+            };
+            let remaining = self.max_elapsed.saturating_sub(backoff.get_elapsed_time());
+            let wait = match (retry_after, backoff.next_backoff()) {
+                (Some(wait), Some(_)) if wait <= remaining => wait,
+                (None, Some(wait)) => wait,
+                _ => return Err(RequestError::Unavailable(error.to_string()).into()),
+            };
+            warn!("{} failed: {}, retry in {:?}", what, error, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// A provider whose requests are retried according to a `RetryPolicy`.
+pub struct Retrying<P> {
+    pub inner: P,
+    pub policy: RetryPolicy,
+}
+
+#[async_trait]
+impl<P: ChatProvider> ChatProvider for Retrying<P> {
+    async fn chat_complete(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatResponse> {
+        self.policy
+            .retry("Chat completion", || {
+                self.inner
+                    .chat_complete(model, generation, conversation.clone())
+            })
+            .await
+    }
+
+    async fn chat_complete_stream(
+        &self,
+        model: &str,
+        generation: &GenerationConfig,
+        conversation: ConversationCtx,
+    ) -> Result<ChatStream> {
+        self.policy
+            .retry("Open chat stream", || {
+                self.inner
+                    .chat_complete_stream(model, generation, conversation.clone())
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl<P: EmbeddingProvider> EmbeddingProvider for Retrying<P> {
+    async fn embedding(&self, text: &str) -> Result<Vec<f32>> {
+        self.policy
+            .retry("Embedding", || self.inner.embedding(text))
+            .await
+    }
+
+    async fn embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        self.policy
+            .retry("Embeddings", || self.inner.embeddings(texts))
+            .await
+    }
+
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use anyhow::Result;
+    use async_openai::error::{ApiError, OpenAIError};
+    use reqwest::StatusCode;
+
+    use super::{
+        classify, classify_failure, parse_retry_after, retry_after_hint, ApiFailure, Failure,
+        RequestError, RetryPolicy,
+    };
+
+    fn api_error(r#type: &str, code: Option<&str>, message: &str) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: message.into(),
+            r#type: r#type.into(),
+            param: None,
+            code: code.map(|x| x.into()),
+        })
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            retry_after_hint("Rate limit reached. Please try again in 20s."),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            retry_after_hint("Please retry after 1.5 seconds."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after_hint("Please try again in 200ms."),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(retry_after_hint("The server is overloaded."), None);
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_747);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        let rate_limited = api_error("requests", None, "Please try again in 2s.");
+        assert_eq!(
+            classify(&rate_limited),
+            Failure::Retryable(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            classify(&api_error("server_error", None, "The server had an error")),
+            Failure::Retryable(None)
+        );
+        assert!(matches!(
+            classify(&api_error(
+                "invalid_request_error",
+                Some("invalid_api_key"),
+                "Incorrect API key provided"
+            )),
+            Failure::Fatal(RequestError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            classify(&api_error(
+                "invalid_request_error",
+                Some("context_length_exceeded"),
+                "This model's maximum context length is 4097 tokens."
+            )),
+            Failure::Fatal(RequestError::ContextLengthExceeded(_))
+        ));
+        assert!(matches!(
+            classify(&api_error(
+                "insufficient_quota",
+                None,
+                "You exceeded your quota"
+            )),
+            Failure::Fatal(RequestError::QuotaExceeded(_))
+        ));
+
+        // The status decides before the body, which may not even be an error object.
+        let failure = |status, error| ApiFailure {
+            status,
+            error,
+            retry_after: None,
+        };
+        let page = || api_error("", None, "<html>Not Found</html>");
+        assert!(matches!(
+            classify_failure(&failure(StatusCode::NOT_FOUND, page())),
+            Failure::Fatal(RequestError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            classify_failure(&failure(StatusCode::FORBIDDEN, page())),
+            Failure::Fatal(RequestError::Unauthorized(_))
+        ));
+        assert_eq!(
+            classify_failure(&failure(StatusCode::BAD_GATEWAY, page())),
+            Failure::Retryable(None)
+        );
+        assert_eq!(
+            classify_failure(&failure(StatusCode::REQUEST_TIMEOUT, page())),
+            Failure::Retryable(None)
+        );
+        assert!(matches!(
+            classify_failure(&failure(
+                StatusCode::TOO_MANY_REQUESTS,
+                api_error("insufficient_quota", None, "You exceeded your quota")
+            )),
+            Failure::Fatal(RequestError::QuotaExceeded(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            initial_interval: Duration::from_millis(1),
+            max_interval: Duration::from_millis(5),
+            max_elapsed: Duration::from_secs(1),
+        };
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<usize> = policy
+            .retry("Test", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(api_error("server_error", None, "Try again in 10ms").into()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = policy
+            .retry("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(api_error("invalid_request_error", None, "Bad request").into())
+            })
+            .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<RequestError>(),
+            Some(RequestError::InvalidRequest(_))
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // The `Retry-After` header takes precedence over the message.
+        let rate_limited = |retry_after| ApiFailure {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            error: api_error("requests", None, "Please try again in 1ms."),
+            retry_after: Some(retry_after),
+        };
+        let attempts = AtomicUsize::new(0);
+        let result: Result<()> = policy
+            .retry("Test", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(rate_limited(Duration::from_secs(20)).into())
+            })
+            .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<RequestError>(),
+            Some(RequestError::Unavailable(_))
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        let attempts = AtomicUsize::new(0);
+        let started = tokio::time::Instant::now();
+        let result: Result<usize> = policy
+            .retry("Test", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(rate_limited(Duration::from_millis(50)).into()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // An api asking to wait longer than the time left isn't waited for.
+        let result: Result<()> = policy
+            .retry("Test", || async {
+                Err(api_error("requests", None, "Please try again in 20s.").into())
+            })
+            .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<RequestError>(),
+            Some(RequestError::Unavailable(_))
+        ));
+    }
+}
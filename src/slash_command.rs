@@ -0,0 +1,331 @@
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Result};
+use log_error::LogError;
+use serenity::{
+    model::application::{
+        command::{Command, CommandOptionType},
+        interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+    },
+    prelude::*,
+};
+use tracing::info;
+
+use crate::{
+    config::ChannelSettings,
+    conversation::{participant_name, ConversationCtx, ConversationKey, MessageOrigin},
+    failure::AnswerError,
+    msg_handler::Handler,
+    retrieval::{with_sources, Passage},
+    splitter::{split_message, DISCORD_MESSAGE_LIMIT},
+};
+
+/// Number of characters of a message shown by `/history`.
+const HISTORY_PREVIEW_LENGTH: usize = 200;
+
+/// Register the application commands of the bot.
+pub async fn register_commands(ctx: &Context) -> Result<()> {
+    let commands = Command::set_global_application_commands(&ctx.http, |commands| {
+        commands
+            .create_application_command(|command| {
+                command
+                    .name("ask")
+                    .description("Ask the bot a question")
+                    .create_option(|option| {
+                        option
+                            .name("question")
+                            .description("The question")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+            })
+            .create_application_command(|command| {
+                command
+                    .name("reset")
+                    .description("Forget your conversation with the bot")
+            })
+            .create_application_command(|command| {
+                command
+                    .name("history")
+                    .description("Show the conversation the bot remembers")
+            })
+            .create_application_command(|command| {
+                command
+                    .name("sources")
+                    .description("Show the knowledge used for the last answer")
+            })
+            .create_application_command(|command| {
+                command
+                    .name("status")
+                    .description("Show whether the bot can use its knowledge")
+            })
+    })
+    .await?;
+    info!("Registered {} application commands", commands.len());
+    Ok(())
+}
+
+/// Render the messages of a conversation, shortening long ones.
+pub fn format_history(conversation: &ConversationCtx) -> String {
+    if conversation.is_empty() && conversation.summary.is_none() {
+        return "I don't remember anything of this conversation.".into();
+    }
+    let preview = |text: &str| {
+        let mut content: String = text.chars().take(HISTORY_PREVIEW_LENGTH).collect();
+        if content.len() < text.len() {
+            content.push('…');
+        }
+        content
+    };
+    let summary = conversation
+        .summary
+        .as_ref()
+        .map(|x| format!("**summary**: {}", preview(x)));
+    summary
+        .into_iter()
+        .chain(conversation.iter().map(|message| {
+            let content = preview(&message.content);
+            match &message.name {
+                Some(name) => format!("**{}** ({}): {}", message.role, name, content),
+                None => format!("**{}**: {}", message.role, content),
+            }
+        }))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_passages_used(passages: &[Passage]) -> String {
+    if passages.is_empty() {
+        return "No knowledge was used for the last answer.".into();
+    }
+    passages
+        .iter()
+        .map(|x| match x.url.is_empty() {
+            true => format!("[{}] {}", x.number, x.title),
+            false => format!("[{}] [{}](<{}>)", x.number, x.title, x.url),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Handler {
+    pub(crate) async fn _interaction(
+        &self,
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+    ) -> Result<(), AnswerError> {
+        info!(
+            "Command /{} used by {:?}",
+            command.data.name, command.user.name
+        );
+
+        let settings = self.settings_for(command.guild_id, command.channel_id);
+        if !settings.allows(command.guild_id, command.channel_id) {
+            respond_ephemeral(ctx, command, "I don't answer in this channel.").await?;
+            return Ok(());
+        }
+        let key = self
+            .conversation_key(ctx, command.user.id, command.channel_id)
+            .await?;
+        match command.data.name.as_str() {
+            "ask" => return self.ask(ctx, command, &settings, key).await,
+            "reset" => {
+                self.conversation_store
+                    .clear_messages(key)
+                    .map_err(|x| AnswerError::Conversation(x.into()))?;
+                self.last_passages.clear(key);
+                respond_ephemeral(ctx, command, "I forgot our conversation.").await?
+            }
+            "history" => {
+                let conversation = self
+                    .conversation_store
+                    .get_messages(key)
+                    .map_err(|x| AnswerError::Conversation(x.into()))?;
+                respond_ephemeral(ctx, command, &format_history(&conversation)).await?
+            }
+            "sources" => {
+                let sources = format_passages_used(&self.last_passages.get(key));
+                respond_ephemeral(ctx, command, &sources).await?
+            }
+            "status" => {
+                let status = self.format_status(&settings);
+                respond_ephemeral(ctx, command, &status).await?
+            }
+            name => return Err(anyhow!("Unknown command: {}", name).into()),
+        }
+        Ok(())
+    }
+
+    fn format_status(&self, settings: &ChannelSettings) -> String {
+        let knowledge = match self.knowledge_client.down_since() {
+            None => format!("available, collection `{}`", settings.collection),
+            Some(since) => format!(
+                "unreachable since <t:{}:R>, answering without knowledge",
+                since
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ),
+        };
+        let model = self.config.get().model_spec(&settings.model);
+        format!(
+            "**Knowledge base**: {}\n**Model**: {} ({} tokens of context)",
+            knowledge, settings.model, model.context_window
+        )
+    }
+
+    /// Answer the question of `/ask` like a question mentioning the bot.
+    async fn ask(
+        &self,
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+        settings: &ChannelSettings,
+        key: ConversationKey,
+    ) -> Result<(), AnswerError> {
+        let question = command
+            .data
+            .options
+            .iter()
+            .find(|x| x.name == "question")
+            .and_then(|x| x.value.as_ref())
+            .and_then(|x| x.as_str())
+            .ok_or_else(|| anyhow!("Missing question"))?
+            .to_string();
+
+        if let Err(limited) =
+            self.rate_limiter
+                .check(command.user.id, command.channel_id, command.guild_id)
+        {
+            info!("Limited {:?}: {:?}", command.user.name, limited);
+            respond_ephemeral(ctx, command, &limited.reply()).await?;
+            return Ok(());
+        }
+
+        // Answering takes longer than discord waits for a response.
+        command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+
+        let name = key
+            .is_shared()
+            .then(|| participant_name(&command.user.name));
+        let origin = MessageOrigin {
+            user_id: command.user.id,
+            channel_id: command.channel_id,
+            guild_id: command.guild_id,
+        };
+        let (conversation, passages) = self
+            .prepare_answer(settings, key, &question, name.clone(), None, origin)
+            .await?;
+
+        let prompt_tokens = self.prompt_tokens(settings, &conversation)?;
+        let response = self
+            .chat_provider
+            .chat_complete(&settings.model, &settings.generation, conversation)
+            .await?;
+        let content = format!(
+            "> {}\n\n{}",
+            question,
+            with_sources(&response.content, &passages)
+        );
+        for (i, part) in split_message(&content, DISCORD_MESSAGE_LIMIT)
+            .into_iter()
+            .enumerate()
+        {
+            match i {
+                0 => {
+                    command
+                        .edit_original_interaction_response(&ctx.http, |r| r.content(part))
+                        .await?;
+                }
+                _ => {
+                    command
+                        .create_followup_message(&ctx.http, |m| m.content(part))
+                        .await?;
+                }
+            }
+        }
+
+        self.record_chat_usage(origin, &settings.model, prompt_tokens, &response);
+        self.remember_answer(
+            key,
+            [(&question, name), (&response.content, None)],
+            passages,
+            origin,
+        );
+        self.summarize_history(settings, key, origin)
+            .await
+            .log_error("Summarize conversation failed");
+        Ok(())
+    }
+}
+
+/// Tell the user of `command` that it failed, whether it was responded to already or not.
+pub(crate) async fn tell_failure(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    content: &str,
+) {
+    let responded = command
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(content).ephemeral(true))
+        })
+        .await;
+    if responded.is_err() {
+        command
+            .edit_original_interaction_response(&ctx.http, |r| r.content(content))
+            .await
+            .log_error("Reply failure failed");
+    }
+}
+
+/// Respond to `command` with a message only its user sees.
+async fn respond_ephemeral(
+    ctx: &Context,
+    command: &ApplicationCommandInteraction,
+    content: &str,
+) -> Result<()> {
+    let mut parts = split_message(content, DISCORD_MESSAGE_LIMIT).into_iter();
+    let first = parts.next().unwrap_or_default();
+    command
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(first).ephemeral(true))
+        })
+        .await?;
+    for part in parts {
+        command
+            .create_followup_message(&ctx.http, |m| m.content(part).ephemeral(true))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_history;
+    use crate::conversation::ConversationCtx;
+
+    #[test]
+    fn test_format_history() {
+        let mut conversation = ConversationCtx::default();
+        assert!(format_history(&conversation).starts_with("I don't remember"));
+
+        conversation.add_user_message("How do I start it?", Some("alice".into()));
+        conversation.add_assistant_message(&"Run it. ".repeat(40), None);
+        let history = format_history(&conversation);
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines[0], "**user** (alice): How do I start it?");
+        assert!(lines[1].starts_with("**assistant**: Run it."));
+        assert!(lines[1].ends_with('…'));
+
+        conversation.summary = Some("Alice asked how to start it.".into());
+        let history = format_history(&conversation);
+        assert!(history.starts_with("**summary**: Alice asked how to start it.\n"));
+    }
+}
@@ -0,0 +1,181 @@
+/// Maximal number of characters discord accepts in the content of one message.
+pub const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+const FENCE: &str = "```";
+
+/// Split `text` into parts of at most `limit` characters. Parts are cut on paragraph, line or
+/// sentence boundaries where possible, and preferably outside of fenced code blocks. A code block
+/// which has to be cut anyway is closed at the end of the part and reopened, with the same
+/// language tag, at the start of the next one.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+    let mut reopen: Option<String> = None;
+
+    while !rest.is_empty() {
+        let prefix = reopen
+            .as_ref()
+            .map(|fence| format!("{}\n", fence))
+            .unwrap_or_default();
+        if char_len(&prefix) + char_len(rest) <= limit {
+            parts.push(prefix + rest);
+            break;
+        }
+
+        let mut budget = limit.saturating_sub(char_len(&prefix)).max(1);
+        if reopen.is_some() || rest[..byte_index(rest, budget)].contains(FENCE) {
+            // Leave room to close a code block which may be open at the cut.
+            budget = budget.saturating_sub(FENCE.len() + 1).max(1);
+        }
+        let window = &rest[..byte_index(rest, budget)];
+        let cut = find_cut(window, reopen.is_some());
+        let (head, tail) = rest.split_at(cut);
+
+        let open_fence = fence_after(reopen, head);
+        let mut part = prefix + head.trim_end();
+        if open_fence.is_some() {
+            part.push('\n');
+            part.push_str(FENCE);
+        }
+        parts.push(part);
+
+        rest = match open_fence {
+            // Keep the indentation of code, only drop the line break we cut at.
+            Some(_) => tail.strip_prefix('\n').unwrap_or(tail),
+            None => tail.trim_start(),
+        };
+        reopen = open_fence;
+    }
+
+    parts
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Byte index of the `chars`-th character of `text`, or its length if it's shorter.
+fn byte_index(text: &str, chars: usize) -> usize {
+    text.char_indices()
+        .nth(chars)
+        .map(|(index, _)| index)
+        .unwrap_or(text.len())
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with(FENCE)
+}
+
+/// The opening fence line of the code block which is still open after `text`, given the one
+/// open before it.
+fn fence_after(mut open: Option<String>, text: &str) -> Option<String> {
+    for line in text.lines() {
+        if is_fence(line) {
+            open = match open {
+                Some(_) => None,
+                None => Some(line.trim().to_string()),
+            };
+        }
+    }
+    open
+}
+
+/// Pick the byte index where `window` should be cut. Candidates are ranked, and a candidate is
+/// only taken if it keeps at least half of the window, so parts don't get needlessly short.
+fn find_cut(window: &str, in_fence: bool) -> usize {
+    let mut paragraphs = Vec::new();
+    let mut lines = Vec::new();
+    let mut sentences = Vec::new();
+    let mut code_lines = Vec::new();
+
+    let mut in_fence = in_fence;
+    let mut offset = 0;
+    for line in window.split_inclusive('\n') {
+        let end = offset + line.len();
+        if is_fence(line) {
+            in_fence = !in_fence;
+        } else if !in_fence {
+            for (index, _) in line.match_indices(['.', '!', '?']) {
+                let after = &line[index + 1..];
+                if after.starts_with(' ') || after == "\n" {
+                    sentences.push(offset + index + 1);
+                }
+            }
+        }
+
+        if line.ends_with('\n') {
+            match in_fence {
+                true => code_lines.push(end),
+                false if line.trim().is_empty() => paragraphs.push(end),
+                false => lines.push(end),
+            }
+        }
+        offset = end;
+    }
+
+    let half = window.len() / 2;
+    [paragraphs, lines, sentences, code_lines]
+        .iter()
+        .find_map(|candidates| candidates.iter().rev().find(|&&cut| cut >= half).copied())
+        .or_else(|| window.rfind(char::is_whitespace).filter(|&cut| cut > 0))
+        .unwrap_or(window.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{char_len, split_message};
+
+    #[test]
+    fn test_short_message_is_not_split() {
+        assert_eq!(split_message("Hello there.", 2000), vec!["Hello there."]);
+        assert!(split_message("   ", 2000).is_empty());
+    }
+
+    #[test]
+    fn test_split_on_paragraphs_and_sentences() {
+        let text = "First paragraph is here.\n\nSecond paragraph. It has two sentences.";
+        let parts = split_message(text, 45);
+        assert_eq!(
+            parts,
+            vec![
+                "First paragraph is here.",
+                "Second paragraph. It has two sentences."
+            ]
+        );
+
+        let text = "One sentence here. Another sentence there. And a third one.";
+        let parts = split_message(text, 45);
+        assert_eq!(
+            parts,
+            vec![
+                "One sentence here. Another sentence there.",
+                "And a third one."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_inside_code_block_reopens_fence() {
+        let code: String = (0..30).map(|i| format!("let x{} = {};\n", i, i)).collect();
+        let text = format!("Here you go:\n```rust\n{}```\nDone.", code);
+        let parts = split_message(&text, 200);
+
+        assert!(parts.len() > 2);
+        for part in parts.iter() {
+            assert!(char_len(part) <= 200);
+            assert_eq!(part.matches("```").count() % 2, 0, "{}", part);
+        }
+        assert!(parts[1..].iter().all(|part| part.starts_with("```rust\n")));
+
+        let joined = parts.join("\n").replace("\n```\n```rust\n", "\n");
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn test_split_without_boundaries() {
+        let text = "ü".repeat(45);
+        let parts = split_message(&text, 20);
+        assert!(parts.iter().all(|part| char_len(part) <= 20));
+        assert_eq!(parts.concat(), text);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_openai::types::Role;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::trace;
+
+use crate::conversation::{
+    ConversationCache, ConversationCacheError, ConversationCtx, ConversationKey, ConversationStore,
+    MessageOrigin,
+};
+
+/// Conversation history persisted in a SQLite database, so it survives restarts. Conversations
+/// are loaded into an in-memory `ConversationCache` the first time they are needed.
+pub struct SqliteConversationStore {
+    connection: Mutex<Connection>,
+    cache: ConversationCache,
+}
+
+impl SqliteConversationStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ConversationCacheError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id      INTEGER NOT NULL,
+                role         TEXT    NOT NULL,
+                content      TEXT    NOT NULL,
+                name         TEXT,
+                timestamp    INTEGER NOT NULL,
+                channel_id   INTEGER NOT NULL,
+                guild_id     INTEGER,
+                conversation TEXT
+            );
+            CREATE TABLE IF NOT EXISTS summaries (
+                conversation TEXT    PRIMARY KEY,
+                content      TEXT    NOT NULL,
+                until_id     INTEGER NOT NULL
+            );",
+        )?;
+
+        // Databases written before conversations were scoped only hold per user conversations.
+        let has_conversation = connection
+            .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'conversation'")?
+            .exists([])?;
+        if !has_conversation {
+            connection.execute_batch(
+                "ALTER TABLE messages ADD COLUMN conversation TEXT;
+                UPDATE messages SET conversation = 'user:' || user_id;",
+            )?;
+        }
+        connection.execute_batch(
+            "CREATE INDEX IF NOT EXISTS messages_conversation ON messages (conversation, id);",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            cache: ConversationCache::default(),
+        })
+    }
+
+    /// The summary of a conversation, and the id of the last message it condenses.
+    fn load_summary(
+        connection: &Connection,
+        key: ConversationKey,
+    ) -> Result<(Option<String>, i64), ConversationCacheError> {
+        let summary = connection
+            .query_row(
+                "SELECT content, until_id FROM summaries WHERE conversation = ?1",
+                params![key.to_string()],
+                |row| Ok((Some(row.get(0)?), row.get(1)?)),
+            )
+            .optional()?;
+        Ok(summary.unwrap_or((None, 0)))
+    }
+
+    fn load_messages(
+        &self,
+        key: ConversationKey,
+    ) -> Result<ConversationCtx, ConversationCacheError> {
+        let connection = self.connection.lock()?;
+        let (summary, until_id) = Self::load_summary(&connection, key)?;
+        let mut statement = connection.prepare(
+            "SELECT role, content, name FROM messages
+            WHERE conversation = ?1 AND id > ?3 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = statement.query_map(
+            params![
+                key.to_string(),
+                self.cache.max_conversation_length as i64,
+                until_id
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )?;
+
+        let mut ctx = ConversationCtx {
+            summary,
+            ..Default::default()
+        };
+        for row in rows {
+            let (role, content, name) = row?;
+            ctx.add_message(parse_role(&role), &content, name);
+        }
+        ctx.value.make_contiguous().reverse();
+        trace!("Loaded {} messages of {}", ctx.value.len(), key);
+        Ok(ctx)
+    }
+}
+
+fn parse_role(role: &str) -> Role {
+    match role {
+        "system" => Role::System,
+        "assistant" => Role::Assistant,
+        _ => Role::User,
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl ConversationStore for SqliteConversationStore {
+    fn add_message(
+        &self,
+        key: ConversationKey,
+        role: Role,
+        message: &str,
+        name: Option<String>,
+        origin: MessageOrigin,
+    ) -> Result<(), ConversationCacheError> {
+        self.connection.lock()?.execute(
+            "INSERT INTO messages
+            (user_id, role, content, name, timestamp, channel_id, guild_id, conversation)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                origin.user_id.0 as i64,
+                role.to_string(),
+                message,
+                name,
+                now(),
+                origin.channel_id.0 as i64,
+                origin.guild_id.map(|x| x.0 as i64),
+                key.to_string(),
+            ],
+        )?;
+
+        // Conversations which aren't cached yet are loaded with this message on the next read.
+        if self.cache.contains(key)? {
+            self.cache.add_message(key, role, message, name)?;
+        }
+        Ok(())
+    }
+
+    fn get_messages(
+        &self,
+        key: ConversationKey,
+    ) -> Result<ConversationCtx, ConversationCacheError> {
+        if self.cache.contains(key)? {
+            return self.cache.get_messages(key);
+        }
+        let ctx = self.load_messages(key)?;
+        self.cache.put_messages(key, ctx.clone())?;
+        Ok(ctx)
+    }
+
+    fn clear_messages(&self, key: ConversationKey) -> Result<(), ConversationCacheError> {
+        let connection = self.connection.lock()?;
+        for table in ["messages", "summaries"] {
+            connection.execute(
+                &format!("DELETE FROM {} WHERE conversation = ?1", table),
+                params![key.to_string()],
+            )?;
+        }
+        self.cache.clear_messages(key)
+    }
+
+    fn summarize(
+        &self,
+        key: ConversationKey,
+        summary: &str,
+        count: usize,
+    ) -> Result<(), ConversationCacheError> {
+        {
+            let connection = self.connection.lock()?;
+            let (_, until_id) = Self::load_summary(&connection, key)?;
+            // The messages loaded for the conversation, of which the oldest are summarized.
+            let mut statement = connection.prepare(
+                "SELECT id FROM messages
+                WHERE conversation = ?1 AND id > ?2 ORDER BY id DESC LIMIT ?3",
+            )?;
+            let ids = statement
+                .query_map(
+                    params![
+                        key.to_string(),
+                        until_id,
+                        self.cache.max_conversation_length as i64
+                    ],
+                    |row| row.get::<_, i64>(0),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            let until_id = match count {
+                0 => until_id,
+                count => ids[ids.len().saturating_sub(count)..]
+                    .first()
+                    .copied()
+                    .unwrap_or(until_id),
+            };
+            connection.execute(
+                "INSERT INTO summaries (conversation, content, until_id) VALUES (?1, ?2, ?3)
+                ON CONFLICT (conversation) DO UPDATE
+                SET content = excluded.content, until_id = excluded.until_id",
+                params![key.to_string(), summary, until_id],
+            )?;
+        }
+
+        if self.cache.contains(key)? {
+            self.cache.summarize(key, summary, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+    use serenity::model::prelude::{ChannelId, UserId};
+
+    use super::SqliteConversationStore;
+    use crate::conversation::{ConversationKey, ConversationStore, MessageOrigin};
+
+    #[test]
+    fn test_conversation_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let origin = MessageOrigin {
+            user_id: UserId(1),
+            channel_id: ChannelId(2),
+            guild_id: None,
+        };
+        let key = ConversationKey::UserChannel(UserId(1), ChannelId(2));
+
+        let store = SqliteConversationStore::open(&path).unwrap();
+        store
+            .add_message(key, Role::User, "Hello", None, origin)
+            .unwrap();
+        assert_eq!(store.get_messages(key).unwrap().len(), 1);
+        store
+            .add_message(key, Role::Assistant, "Hi!", None, origin)
+            .unwrap();
+        drop(store);
+
+        let store = SqliteConversationStore::open(&path).unwrap();
+        let ctx = store.get_messages(key).unwrap();
+        assert_eq!(ctx.len(), 2);
+        assert_eq!(ctx[0].content, "Hello");
+        assert_eq!(ctx[1].content, "Hi!");
+        assert!(store
+            .get_messages(ConversationKey::User(UserId(1)))
+            .unwrap()
+            .is_empty());
+
+        store.clear_messages(key).unwrap();
+        drop(store);
+        let store = SqliteConversationStore::open(&path).unwrap();
+        assert!(store.get_messages(key).unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_summary_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let origin = MessageOrigin {
+            user_id: UserId(1),
+            channel_id: ChannelId(2),
+            guild_id: None,
+        };
+        let key = ConversationKey::User(UserId(1));
+
+        let store = SqliteConversationStore::open(&path).unwrap();
+        for content in ["My name is Alice", "Hi Alice!", "What's new?", "Nothing."] {
+            store
+                .add_message(key, Role::User, content, None, origin)
+                .unwrap();
+        }
+        assert_eq!(store.get_messages(key).unwrap().len(), 4);
+        store.summarize(key, "The user is Alice.", 2).unwrap();
+        let ctx = store.get_messages(key).unwrap();
+        assert_eq!(ctx.summary.as_deref(), Some("The user is Alice."));
+        assert_eq!(ctx[0].content, "What's new?");
+        drop(store);
+
+        let store = SqliteConversationStore::open(&path).unwrap();
+        let ctx = store.get_messages(key).unwrap();
+        assert_eq!(ctx.summary.as_deref(), Some("The user is Alice."));
+        assert_eq!(ctx.len(), 2);
+        assert_eq!(ctx[0].content, "What's new?");
+
+        store.clear_messages(key).unwrap();
+        drop(store);
+        let store = SqliteConversationStore::open(&path).unwrap();
+        assert!(store.get_messages(key).unwrap().summary.is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
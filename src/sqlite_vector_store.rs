@@ -0,0 +1,436 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::vector_store::{
+    CollectionConfig, CollectionInfo, Filter, Payload, ScoredPoint, ScrollPage, VectorPoint,
+    VectorStore,
+};
+
+/// Collections kept in a SQLite database, searched by comparing the query with every vector of
+/// a collection. Needs no server, and is fast enough for knowledge bases of some thousand
+/// chunks. The database is used on the blocking threads of tokio, so a search never holds up
+/// other tasks.
+pub struct SqliteVectorStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect()
+}
+
+impl SqliteVectorStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS collections (
+                name       TEXT PRIMARY KEY,
+                config     TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS points (
+                collection TEXT NOT NULL,
+                id         TEXT NOT NULL,
+                vector     BLOB NOT NULL,
+                payload    TEXT NOT NULL,
+                PRIMARY KEY (collection, id)
+            );",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` with the connection on a blocking thread.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| anyhow!("Vector store lock is poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+
+    fn config(connection: &Connection, collection: &str) -> Result<CollectionConfig> {
+        let config: Option<String> = connection
+            .query_row(
+                "SELECT config FROM collections WHERE name = ?1",
+                params![collection],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match config {
+            Some(config) => Ok(serde_json::from_str(&config)?),
+            None => Err(anyhow!("No collection {}", collection)),
+        }
+    }
+
+    /// Up to `limit` points of `collection` matching `filter` from `offset` on, ordered by id,
+    /// with their vectors if `with_vectors`. Rows are only read and decoded until `limit` points
+    /// are found.
+    fn points(
+        connection: &Connection,
+        collection: &str,
+        filter: Option<&Filter>,
+        offset: Option<&str>,
+        limit: Option<usize>,
+        with_vectors: bool,
+    ) -> Result<Vec<VectorPoint>> {
+        Self::config(connection, collection)?;
+        // Without a filter every row is a match, so the limit is left to SQLite.
+        let sql_limit = match filter {
+            None => limit.map_or(-1, |x| x as i64),
+            Some(_) => -1,
+        };
+        let mut statement = connection.prepare(
+            "SELECT id, CASE WHEN ?3 THEN vector ELSE x'' END, payload FROM points
+            WHERE collection = ?1 AND id >= ?2 ORDER BY id LIMIT ?4",
+        )?;
+        let mut rows = statement.query(params![
+            collection,
+            offset.unwrap_or_default(),
+            with_vectors,
+            sql_limit
+        ])?;
+        let mut points = Vec::new();
+        while let Some(row) = rows.next()? {
+            if limit.is_some_and(|x| points.len() >= x) {
+                break;
+            }
+            let payload: Payload = serde_json::from_str(row.get_ref(2)?.as_str()?)?;
+            if filter.is_none_or(|x| x.matches(&payload)) {
+                points.push(VectorPoint {
+                    id: row.get(0)?,
+                    vector: decode_vector(row.get_ref(1)?.as_blob()?),
+                    payload,
+                });
+            }
+        }
+        Ok(points)
+    }
+}
+
+#[async_trait]
+impl VectorStore for SqliteVectorStore {
+    async fn health_check(&self) -> Result<()> {
+        self.with_connection(|_| Ok(())).await
+    }
+
+    async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let config = match Self::config(connection, &collection) {
+                Ok(config) => config,
+                Err(_) => return Ok(None),
+            };
+            let points: i64 = connection.query_row(
+                "SELECT COUNT(*) FROM points WHERE collection = ?1",
+                params![collection],
+                |row| row.get(0),
+            )?;
+            Ok(Some(CollectionInfo {
+                config,
+                points: points as u64,
+                status: "Ready".into(),
+            }))
+        })
+        .await
+    }
+
+    async fn create_collection(&self, collection: &str, config: &CollectionConfig) -> Result<()> {
+        let collection = collection.to_string();
+        let config = serde_json::to_string(config)?;
+        self.with_connection(move |connection| {
+            let inserted = connection.execute(
+                "INSERT OR IGNORE INTO collections (name, config) VALUES (?1, ?2)",
+                params![collection, config],
+            )?;
+            match inserted {
+                0 => Err(anyhow!("Collection {} already exists", collection)),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn delete_collection(&self, collection: &str) -> Result<()> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM points WHERE collection = ?1",
+                params![collection],
+            )?;
+            transaction.execute(
+                "DELETE FROM collections WHERE name = ?1",
+                params![collection],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<VectorPoint>) -> Result<()> {
+        let collection = collection.to_string();
+        self.with_connection(move |connection| {
+            let config = Self::config(connection, &collection)?;
+            let transaction = connection.transaction()?;
+            for point in points {
+                if point.vector.len() as u64 != config.vector_size {
+                    return Err(anyhow!(
+                        "Vector of point {} has {} dimensions, {} expects {}",
+                        point.id,
+                        point.vector.len(),
+                        collection,
+                        config.vector_size
+                    ));
+                }
+                transaction.execute(
+                    "INSERT OR REPLACE INTO points (collection, id, vector, payload)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        collection,
+                        point.id,
+                        encode_vector(&point.vector),
+                        serde_json::to_string(&point.payload)?
+                    ],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<&Filter>,
+        limit: usize,
+        score_threshold: Option<f32>,
+    ) -> Result<Vec<ScoredPoint>> {
+        let collection = collection.to_string();
+        let vector = vector.to_vec();
+        let filter = filter.cloned();
+        self.with_connection(move |connection| {
+            let distance = Self::config(connection, &collection)?.distance;
+            let mut statement = connection
+                .prepare("SELECT id, vector, payload FROM points WHERE collection = ?1")?;
+            let mut rows = statement.query(params![collection])?;
+            // Payloads are only decoded to be filtered, and once the closest points are found.
+            let mut found = Vec::new();
+            while let Some(row) = rows.next()? {
+                let score = distance.score(&vector, &decode_vector(row.get_ref(1)?.as_blob()?));
+                if score_threshold.is_some_and(|x| !distance.passes(score, x)) {
+                    continue;
+                }
+                let payload = row.get_ref(2)?.as_str()?;
+                if let Some(filter) = &filter {
+                    if !filter.matches(&serde_json::from_str(payload)?) {
+                        continue;
+                    }
+                }
+                found.push((score, row.get::<_, String>(0)?, payload.to_string()));
+            }
+            found.sort_by(|a, b| distance.compare(a.0, b.0));
+            found.truncate(limit);
+            found
+                .into_iter()
+                .map(|(score, id, payload)| {
+                    Ok(ScoredPoint {
+                        id,
+                        score,
+                        payload: serde_json::from_str(&payload)?,
+                    })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn delete(&self, collection: &str, filter: &Filter) -> Result<()> {
+        let collection = collection.to_string();
+        let filter = filter.clone();
+        self.with_connection(move |connection| {
+            let points = Self::points(connection, &collection, Some(&filter), None, None, false)?;
+            let transaction = connection.transaction()?;
+            for point in points {
+                transaction.execute(
+                    "DELETE FROM points WHERE collection = ?1 AND id = ?2",
+                    params![collection, point.id],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64> {
+        let collection = collection.to_string();
+        let filter = filter.cloned();
+        self.with_connection(move |connection| {
+            if filter.is_none() {
+                Self::config(connection, &collection)?;
+                let points: i64 = connection.query_row(
+                    "SELECT COUNT(*) FROM points WHERE collection = ?1",
+                    params![collection],
+                    |row| row.get(0),
+                )?;
+                return Ok(points as u64);
+            }
+            let points = Self::points(connection, &collection, filter.as_ref(), None, None, false)?;
+            Ok(points.len() as u64)
+        })
+        .await
+    }
+
+    async fn scroll(
+        &self,
+        collection: &str,
+        filter: Option<&Filter>,
+        offset: Option<&str>,
+        limit: usize,
+        with_vectors: bool,
+    ) -> Result<ScrollPage> {
+        let collection = collection.to_string();
+        let filter = filter.cloned();
+        let offset = offset.map(String::from);
+        self.with_connection(move |connection| {
+            // One point more than the page tells where the next page starts.
+            let mut points = Self::points(
+                connection,
+                &collection,
+                filter.as_ref(),
+                offset.as_deref(),
+                Some(limit + 1),
+                with_vectors,
+            )?;
+            let next = points.get(limit).map(|x| x.id.clone());
+            points.truncate(limit);
+            Ok((points, next))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::SqliteVectorStore;
+    use crate::vector_store::{
+        CollectionConfig, Condition, Distance, Filter, VectorPoint, VectorStore,
+    };
+
+    fn point(id: &str, vector: Vec<f32>, doc_id: &str) -> VectorPoint {
+        VectorPoint {
+            id: id.into(),
+            vector,
+            payload: json!({ "doc_id": doc_id }).as_object().unwrap().clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_vector_store() {
+        let store = SqliteVectorStore::open(":memory:").unwrap();
+        let config = CollectionConfig {
+            vector_size: 2,
+            distance: Distance::Cosine,
+            indexes: BTreeMap::new(),
+        };
+        assert_eq!(store.collection_info("kb").await.unwrap(), None);
+        store.create_collection("kb", &config).await.unwrap();
+        assert!(store.create_collection("kb", &config).await.is_err());
+
+        store
+            .upsert(
+                "kb",
+                vec![
+                    point("1", vec![1.0, 0.0], "a"),
+                    point("2", vec![0.0, 1.0], "b"),
+                    point("3", vec![1.0, 1.0], "a"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert!(store
+            .upsert("kb", vec![point("4", vec![1.0], "c")])
+            .await
+            .is_err());
+        assert_eq!(
+            store.collection_info("kb").await.unwrap().unwrap().points,
+            3
+        );
+
+        let found = store
+            .search("kb", &[1.0, 0.1], None, 2, None)
+            .await
+            .unwrap();
+        let ids: Vec<&str> = found.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "3"]);
+        let filter = Filter {
+            must: vec![Condition::keyword("doc_id", "b")],
+            ..Default::default()
+        };
+        let found = store
+            .search("kb", &[1.0, 0.1], Some(&filter), 2, None)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, "2");
+        let found = store
+            .search("kb", &[1.0, 0.0], None, 3, Some(0.9))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        let (page, next) = store.scroll("kb", None, None, 2, true).await.unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[1].vector, vec![0.0, 1.0]);
+        assert_eq!(next.as_deref(), Some("3"));
+        let (page, next) = store
+            .scroll("kb", None, next.as_deref(), 2, false)
+            .await
+            .unwrap();
+        assert_eq!((page.len(), next), (1, None));
+        assert!(page[0].vector.is_empty());
+
+        let filter = Filter {
+            must: vec![Condition::keyword("doc_id", "a")],
+            ..Default::default()
+        };
+        assert_eq!(store.count("kb", Some(&filter)).await.unwrap(), 2);
+        let (page, next) = store
+            .scroll("kb", Some(&filter), None, 1, false)
+            .await
+            .unwrap();
+        assert_eq!((page[0].id.as_str(), next.as_deref()), ("1", Some("3")));
+        store.delete("kb", &filter).await.unwrap();
+        assert_eq!(store.count("kb", None).await.unwrap(), 1);
+
+        store.delete_collection("kb").await.unwrap();
+        assert_eq!(store.collection_info("kb").await.unwrap(), None);
+    }
+}
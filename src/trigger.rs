@@ -0,0 +1,83 @@
+use serenity::model::prelude::UserId;
+
+/// The question of a message meant for the bot, or `None` if the message isn't meant for it.
+/// A message is meant for the bot if it mentions the bot anywhere, pings it by replying to it, or
+/// is sent in a direct message. Every mention of the bot is stripped from the question.
+pub fn question_for(bot_id: UserId, content: &str, pinged: bool, direct: bool) -> Option<String> {
+    let (question, mentioned) = strip_mentions(bot_id, content);
+    if !(mentioned || pinged || direct) {
+        return None;
+    }
+    let question = question.trim();
+    match question.is_empty() {
+        true => None,
+        false => Some(question.to_string()),
+    }
+}
+
+/// `content` without the mentions of `bot_id`, like `<@id>` and `<@!id>`, and whether there were
+/// any. Whitespace left doubled or before punctuation by a removed mention is collapsed, and a
+/// leading mention takes the `,` or `:` addressing it along.
+pub fn strip_mentions(bot_id: UserId, content: &str) -> (String, bool) {
+    let mentions = [format!("<@{}>", bot_id.0), format!("<@!{}>", bot_id.0)];
+    let mut stripped = String::with_capacity(content.len());
+    let mut mentioned = false;
+    let mut rest = content;
+    while !rest.is_empty() {
+        match mentions.iter().find(|x| rest.starts_with(x.as_str())) {
+            Some(mention) => {
+                mentioned = true;
+                rest = &rest[mention.len()..];
+                if stripped.trim().is_empty() {
+                    rest = rest.trim_start_matches([',', ':', ' ', '\t']);
+                } else if stripped.ends_with(char::is_whitespace) {
+                    rest = rest.trim_start_matches([' ', '\t']);
+                    if rest.starts_with([',', '.', ';', ':', '!', '?']) {
+                        stripped.truncate(stripped.trim_end_matches([' ', '\t']).len());
+                    }
+                }
+            }
+            None => {
+                let c = rest.chars().next().expect("Unreachable!");
+                stripped.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    (stripped.trim().to_string(), mentioned)
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::prelude::UserId;
+
+    use super::question_for;
+
+    #[test]
+    fn test_question_for() {
+        let bot = UserId(42);
+        let question = |content: &str, pinged, direct| question_for(bot, content, pinged, direct);
+
+        assert_eq!(question("<@42> hi", false, false).as_deref(), Some("hi"));
+        assert_eq!(question("<@!42> hi", false, false).as_deref(), Some("hi"));
+        assert_eq!(
+            question("so <@42>, what is it? <@!42>", false, false).as_deref(),
+            Some("so, what is it?")
+        );
+        assert_eq!(
+            question("<@42>: is it <@42> ?", false, false).as_deref(),
+            Some("is it?")
+        );
+        assert_eq!(
+            question("hey <@42> über <@7>", false, false).as_deref(),
+            Some("hey über <@7>")
+        );
+        assert_eq!(question("a reply", true, false).as_deref(), Some("a reply"));
+        assert_eq!(question("a dm", false, true).as_deref(), Some("a dm"));
+
+        assert_eq!(question("<@7> not for us", false, false), None);
+        assert_eq!(question("<@42>", false, false), None);
+        assert_eq!(question("<@42", false, false), None);
+        assert_eq!(question("ü", false, false), None);
+    }
+}
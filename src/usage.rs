@@ -0,0 +1,397 @@
+use std::{
+    fmt,
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection};
+
+use crate::{ai::TokenUsage, conversation::MessageOrigin};
+
+/// Dollars per 1000 prompt and completion tokens of the OpenAI models, matched by the longest
+/// prefix first. Other models, like self-hosted ones, are free.
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4-32k", 0.06, 0.12),
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-3.5-turbo-16k", 0.003, 0.004),
+    ("gpt-3.5-turbo", 0.0015, 0.002),
+    ("text-embedding-ada-002", 0.0001, 0.0),
+];
+
+/// The estimated cost in dollars of `usage` with `model`.
+pub fn estimate_cost(model: &str, usage: TokenUsage) -> f64 {
+    match PRICES.iter().find(|x| model.starts_with(x.0)) {
+        Some((_, prompt, completion)) => {
+            (usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion)
+                / 1000.0
+        }
+        None => 0.0,
+    }
+}
+
+/// What a request to the api was made for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Chat,
+    Embedding,
+    /// Embedding knowledge into a knowledge base.
+    Ingest,
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestKind::Chat => write!(f, "chat"),
+            RequestKind::Embedding => write!(f, "embedding"),
+            RequestKind::Ingest => write!(f, "ingest"),
+        }
+    }
+}
+
+/// The tokens a request to the api consumed.
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub kind: RequestKind,
+    pub model: String,
+    pub usage: TokenUsage,
+    /// The question the request was made for, none for requests of the command line like
+    /// ingesting knowledge.
+    pub origin: Option<MessageOrigin>,
+}
+
+/// What the usage report sums up by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageGroup {
+    Day,
+    Guild,
+    Channel,
+    User,
+    Model,
+    Kind,
+}
+
+impl UsageGroup {
+    fn column(&self) -> &'static str {
+        match self {
+            UsageGroup::Day => "date(timestamp, 'unixepoch')",
+            UsageGroup::Guild => {
+                "CASE WHEN channel_id IS NULL THEN '-' ELSE COALESCE(CAST(guild_id AS TEXT), 'dm') END"
+            }
+            UsageGroup::Channel => "COALESCE(CAST(channel_id AS TEXT), '-')",
+            UsageGroup::User => "COALESCE(CAST(user_id AS TEXT), '-')",
+            UsageGroup::Model => "model",
+            UsageGroup::Kind => "kind",
+        }
+    }
+}
+
+impl fmt::Display for UsageGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsageGroup::Day => write!(f, "day"),
+            UsageGroup::Guild => write!(f, "guild"),
+            UsageGroup::Channel => write!(f, "channel"),
+            UsageGroup::User => write!(f, "user"),
+            UsageGroup::Model => write!(f, "model"),
+            UsageGroup::Kind => write!(f, "kind"),
+        }
+    }
+}
+
+impl FromStr for UsageGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "guild" => Ok(Self::Guild),
+            "channel" => Ok(Self::Channel),
+            "user" => Ok(Self::User),
+            "model" => Ok(Self::Model),
+            "kind" => Ok(Self::Kind),
+            _ => Err(format!("Unknown usage group: {}", s)),
+        }
+    }
+}
+
+/// The usage summed up for a group, identified by its value of each `UsageGroup`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub keys: Vec<String>,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+/// Tokens and cost of every request to the api, kept in a SQLite database. Clones share the
+/// database connection.
+#[derive(Clone)]
+pub struct UsageStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl UsageStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                id                INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp         INTEGER NOT NULL,
+                kind              TEXT    NOT NULL,
+                model             TEXT    NOT NULL,
+                prompt_tokens     INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                cost              REAL    NOT NULL,
+                user_id           INTEGER,
+                channel_id        INTEGER,
+                guild_id          INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS usage_timestamp ON usage (timestamp);",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn record(&self, record: &UsageRecord) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.record_at(record, timestamp)
+    }
+
+    fn record_at(&self, record: &UsageRecord, timestamp: u64) -> Result<()> {
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow!("Lock poisoned"))?;
+        connection.execute(
+            "INSERT INTO usage (timestamp, kind, model, prompt_tokens, completion_tokens, cost,
+                user_id, channel_id, guild_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                timestamp as i64,
+                record.kind.to_string(),
+                record.model,
+                record.usage.prompt_tokens as i64,
+                record.usage.completion_tokens as i64,
+                estimate_cost(&record.model, record.usage),
+                record.origin.map(|x| x.user_id.0 as i64),
+                record.origin.map(|x| x.channel_id.0 as i64),
+                record.origin.and_then(|x| x.guild_id).map(|x| x.0 as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The usage since `since` seconds after the unix epoch, summed up by `groups`.
+    pub fn report(&self, groups: &[UsageGroup], since: u64) -> Result<Vec<UsageRow>> {
+        let columns: Vec<&str> = groups.iter().map(|x| x.column()).collect();
+        let mut sql = "SELECT COUNT(*), COALESCE(SUM(prompt_tokens), 0),
+                COALESCE(SUM(completion_tokens), 0), COALESCE(SUM(cost), 0.0)"
+            .to_string();
+        for column in columns.iter() {
+            sql.push_str(&format!(", {}", column));
+        }
+        sql.push_str(" FROM usage WHERE timestamp >= ?1");
+        if !columns.is_empty() {
+            let positions: Vec<String> = (5..5 + columns.len()).map(|x| x.to_string()).collect();
+            sql.push_str(&format!(" GROUP BY {0} ORDER BY {0}", positions.join(", ")));
+        }
+
+        let connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow!("Lock poisoned"))?;
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement.query_map(params![since as i64], |row| {
+            Ok(UsageRow {
+                requests: row.get::<_, i64>(0)? as u64,
+                prompt_tokens: row.get::<_, i64>(1)? as u64,
+                completion_tokens: row.get::<_, i64>(2)? as u64,
+                cost: row.get(3)?,
+                keys: (0..columns.len())
+                    .map(|i| row.get(4 + i))
+                    .collect::<Result<_, _>>()?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+fn header(groups: &[UsageGroup]) -> Vec<String> {
+    groups
+        .iter()
+        .map(|x| x.to_string())
+        .chain(
+            ["requests", "prompt_tokens", "completion_tokens", "cost"]
+                .iter()
+                .map(|x| x.to_string()),
+        )
+        .collect()
+}
+
+fn fields(row: &UsageRow) -> Vec<String> {
+    let mut fields = row.keys.clone();
+    fields.push(row.requests.to_string());
+    fields.push(row.prompt_tokens.to_string());
+    fields.push(row.completion_tokens.to_string());
+    fields.push(format!("{:.4}", row.cost));
+    fields
+}
+
+/// The report as a table with aligned columns and a line of totals.
+pub fn format_report(groups: &[UsageGroup], rows: &[UsageRow]) -> String {
+    let mut lines = vec![header(groups)];
+    lines.extend(rows.iter().map(fields));
+    if !groups.is_empty() {
+        let mut total = vec![String::new(); groups.len()];
+        total[0] = "total".into();
+        total.push(rows.iter().map(|x| x.requests).sum::<u64>().to_string());
+        total.push(
+            rows.iter()
+                .map(|x| x.prompt_tokens)
+                .sum::<u64>()
+                .to_string(),
+        );
+        total.push(
+            rows.iter()
+                .map(|x| x.completion_tokens)
+                .sum::<u64>()
+                .to_string(),
+        );
+        total.push(format!("{:.4}", rows.iter().map(|x| x.cost).sum::<f64>()));
+        lines.push(total);
+    }
+
+    let mut widths = vec![0; lines[0].len()];
+    for line in lines.iter() {
+        for (i, field) in line.iter().enumerate() {
+            widths[i] = widths[i].max(field.chars().count());
+        }
+    }
+    lines
+        .iter()
+        .map(|line| {
+            line.iter()
+                .zip(widths.iter())
+                .map(|(field, width)| format!("{:<1$}", field, width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Write the report as CSV, with a header line.
+pub fn write_csv(groups: &[UsageGroup], rows: &[UsageRow], mut writer: impl Write) -> Result<()> {
+    for line in std::iter::once(header(groups)).chain(rows.iter().map(fields)) {
+        let line: Vec<String> = line.iter().map(|x| csv_field(x)).collect();
+        writeln!(writer, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::prelude::{ChannelId, GuildId, UserId};
+
+    use super::{
+        estimate_cost, format_report, write_csv, RequestKind, UsageGroup, UsageRecord, UsageStore,
+    };
+    use crate::{ai::TokenUsage, conversation::MessageOrigin};
+
+    fn record(kind: RequestKind, model: &str, user: u64, guild: Option<u64>) -> UsageRecord {
+        UsageRecord {
+            kind,
+            model: model.into(),
+            usage: TokenUsage {
+                prompt_tokens: 1000,
+                completion_tokens: 500,
+            },
+            origin: Some(MessageOrigin {
+                user_id: UserId(user),
+                channel_id: ChannelId(10),
+                guild_id: guild.map(GuildId),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let usage = TokenUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+        };
+        assert_eq!(estimate_cost("gpt-4-0613", usage), 0.06);
+        assert_eq!(estimate_cost("local-model", usage), 0.0);
+
+        let path = std::env::temp_dir().join(format!("{}.db", uuid::Uuid::new_v4()));
+        let store = UsageStore::open(&path).unwrap();
+        let day = 86_400;
+        store
+            .record_at(&record(RequestKind::Chat, "gpt-4", 1, Some(100)), day)
+            .unwrap();
+        store
+            .record_at(&record(RequestKind::Chat, "gpt-4", 1, Some(100)), 2 * day)
+            .unwrap();
+        store
+            .record_at(&record(RequestKind::Chat, "local", 2, None), 2 * day)
+            .unwrap();
+
+        let groups = [UsageGroup::Day, UsageGroup::Guild];
+        let rows = store.report(&groups, 0).unwrap();
+        let keys: Vec<Vec<String>> = rows.iter().map(|x| x.keys.clone()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                vec!["1970-01-02", "100"],
+                vec!["1970-01-03", "100"],
+                vec!["1970-01-03", "dm"],
+            ]
+        );
+        assert_eq!(rows[0].cost, 0.06);
+        let report = format_report(&groups, &rows);
+        let total = report.lines().last().unwrap();
+        assert_eq!(
+            total.split_whitespace().collect::<Vec<_>>(),
+            vec!["total", "3", "3000", "1500", "0.1200"]
+        );
+
+        let rows = store.report(&[UsageGroup::User], 2 * day).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].requests, 1);
+        let mut csv = Vec::new();
+        write_csv(&[UsageGroup::User], &rows, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "user,requests,prompt_tokens,completion_tokens,cost\n\
+            1,1,1000,500,0.0600\n\
+            2,1,1000,500,0.0000\n"
+        );
+
+        let ingest = UsageRecord {
+            origin: None,
+            ..record(RequestKind::Ingest, "text-embedding-ada-002", 0, None)
+        };
+        store.record_at(&ingest, 3 * day).unwrap();
+        let groups = [UsageGroup::Kind, UsageGroup::Guild, UsageGroup::User];
+        let rows = store.report(&groups, 3 * day).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].keys, vec!["ingest", "-", "-"]);
+        assert!((rows[0].cost - 0.0001).abs() < 1e-9);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+/// Payload of a point, the fields of a JSON object.
+pub type Payload = serde_json::Map<String, serde_json::Value>;
+
+/// How close two vectors are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Distance {
+    Cosine,
+    Euclid,
+    Dot,
+}
+
+impl Distance {
+    /// Score of `b` for a search with `a`: a similarity for `Cosine` and `Dot`, the higher the
+    /// closer, and a distance for `Euclid`, the lower the closer.
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        let dot = || a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        match self {
+            Distance::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norm = norm(a) * norm(b);
+                match norm > 0.0 {
+                    true => dot() / norm,
+                    false => 0.0,
+                }
+            }
+            Distance::Euclid => a
+                .iter()
+                .zip(b)
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum::<f32>()
+                .sqrt(),
+            Distance::Dot => dot(),
+        }
+    }
+
+    /// Order of two scores, the closer first.
+    pub fn compare(&self, a: f32, b: f32) -> Ordering {
+        match self {
+            Distance::Euclid => a.total_cmp(&b),
+            Distance::Cosine | Distance::Dot => b.total_cmp(&a),
+        }
+    }
+
+    /// Whether `score` is as close as `threshold` or closer.
+    pub fn passes(&self, score: f32, threshold: f32) -> bool {
+        self.compare(score, threshold) != Ordering::Greater
+    }
+}
+
+/// Type of the values of an indexed payload field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexType {
+    Keyword,
+    Integer,
+    Float,
+    Geo,
+    Text,
+}
+
+/// How a collection is created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionConfig {
+    pub vector_size: u64,
+    pub distance: Distance,
+    /// Payload fields which are indexed, for stores which index them.
+    pub indexes: BTreeMap<String, IndexType>,
+}
+
+/// What a store knows about a collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionInfo {
+    pub config: CollectionConfig,
+    pub points: u64,
+    /// Condition of the collection, like "Green".
+    pub status: String,
+}
+
+/// A vector with its payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorPoint {
+    /// An unsigned number or a UUID, as Qdrant only accepts those.
+    pub id: String,
+    /// Empty if it wasn't requested.
+    pub vector: Vec<f32>,
+    pub payload: Payload,
+}
+
+/// A point found by a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredPoint {
+    pub id: String,
+    pub score: f32,
+    pub payload: Payload,
+}
+
+/// Points of a scroll, and the offset of the next page if there is one.
+pub type ScrollPage = (Vec<VectorPoint>, Option<String>);
+
+/// A condition on the payload of a point.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// The field is the string, or a list holding it.
+    Keyword {
+        key: String,
+        value: String,
+    },
+    /// The field is the integer, or a list holding it.
+    Integer {
+        key: String,
+        value: i64,
+    },
+    /// The field is missing, null or an empty list.
+    Empty {
+        key: String,
+    },
+    Filter(Filter),
+}
+
+impl Condition {
+    pub fn keyword(key: &str, value: &str) -> Self {
+        Condition::Keyword {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn empty(key: &str) -> Self {
+        Condition::Empty { key: key.into() }
+    }
+
+    pub fn matches(&self, payload: &Payload) -> bool {
+        let field_matches =
+            |key: &str, matches: &dyn Fn(&serde_json::Value) -> bool| match payload.get(key) {
+                Some(serde_json::Value::Array(values)) => values.iter().any(matches),
+                Some(value) => matches(value),
+                None => false,
+            };
+        match self {
+            Condition::Keyword { key, value } => {
+                field_matches(key, &|x| x.as_str() == Some(value.as_str()))
+            }
+            Condition::Integer { key, value } => {
+                field_matches(key, &|x| x.as_i64() == Some(*value))
+            }
+            Condition::Empty { key } => match payload.get(key) {
+                Some(serde_json::Value::Array(values)) => values.is_empty(),
+                Some(value) => value.is_null(),
+                None => true,
+            },
+            Condition::Filter(filter) => filter.matches(payload),
+        }
+    }
+}
+
+impl From<Filter> for Condition {
+    fn from(filter: Filter) -> Self {
+        Condition::Filter(filter)
+    }
+}
+
+/// Selects the points which match all of `must`, any of `should` if there are some, and none of
+/// `must_not`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    pub must: Vec<Condition>,
+    pub should: Vec<Condition>,
+    pub must_not: Vec<Condition>,
+}
+
+impl Filter {
+    pub fn matches(&self, payload: &Payload) -> bool {
+        self.must.iter().all(|x| x.matches(payload))
+            && (self.should.is_empty() || self.should.iter().any(|x| x.matches(payload)))
+            && !self.must_not.iter().any(|x| x.matches(payload))
+    }
+}
+
+/// Keeps the vectors of the knowledge bases and finds the closest ones.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Fails while the store doesn't answer.
+    async fn health_check(&self) -> Result<()>;
+
+    /// The collection, if it exists.
+    async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>>;
+
+    async fn create_collection(&self, collection: &str, config: &CollectionConfig) -> Result<()>;
+
+    async fn delete_collection(&self, collection: &str) -> Result<()>;
+
+    /// Insert the points, replacing the points with the same ids.
+    async fn upsert(&self, collection: &str, points: Vec<VectorPoint>) -> Result<()>;
+
+    /// The `limit` points matching `filter` closest to `vector`, the closest first, leaving out
+    /// the points which aren't as close as `score_threshold`.
+    async fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: Option<&Filter>,
+        limit: usize,
+        score_threshold: Option<f32>,
+    ) -> Result<Vec<ScoredPoint>>;
+
+    async fn delete(&self, collection: &str, filter: &Filter) -> Result<()>;
+
+    async fn count(&self, collection: &str, filter: Option<&Filter>) -> Result<u64>;
+
+    /// Up to `limit` points matching `filter`, from `offset` on, with their vectors if
+    /// `with_vectors`.
+    async fn scroll(
+        &self,
+        collection: &str,
+        filter: Option<&Filter>,
+        offset: Option<&str>,
+        limit: usize,
+        with_vectors: bool,
+    ) -> Result<ScrollPage>;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Condition, Distance, Filter, Payload};
+
+    #[test]
+    fn test_filter_matches() {
+        let payload: Payload = json!({"doc_id": "a", "chunk_index": 1, "tags": ["faq", "setup"]})
+            .as_object()
+            .unwrap()
+            .clone();
+        let filter = Filter {
+            must: vec![Condition::keyword("tags", "faq")],
+            should: vec![
+                Condition::keyword("doc_id", "b"),
+                Condition::keyword("doc_id", "a"),
+            ],
+            must_not: vec![Condition::Integer {
+                key: "chunk_index".into(),
+                value: 0,
+            }],
+        };
+        assert!(filter.matches(&payload));
+        let filter = Filter {
+            must_not: vec![Filter {
+                must: vec![Condition::keyword("doc_id", "a")],
+                ..Default::default()
+            }
+            .into()],
+            ..Default::default()
+        };
+        assert!(!filter.matches(&payload));
+
+        assert!((Distance::Cosine.score(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(Distance::Euclid.score(&[0.0, 0.0], &[3.0, 4.0]), 5.0);
+        assert!(Distance::Euclid.passes(1.0, 2.0));
+        assert!(!Distance::Cosine.passes(0.5, 0.7));
+    }
+}